@@ -0,0 +1,119 @@
+use std::{
+    sync::{Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_api::{
+    db_object::{DataBase, UserEnum, UserFilter},
+    pagination::PageRequest,
+    store::UserStore,
+    User, UserGroup,
+};
+
+const USERS: u32 = 10_000;
+const READERS: [usize; 3] = [1, 4, 8];
+
+fn create_database() -> DataBase {
+    DataBase::from_users(
+        (0..USERS)
+            .map(|id| User {
+                id,
+                name: format!("name{id}"),
+                lastname: format!("lastname{id}"),
+                birth_year: 1950 + (id % 60) as u16,
+                group: UserGroup::User,
+            })
+            .collect(),
+    )
+}
+
+/// The lock shared by the server's workers.
+trait SharedStore: Sync {
+    fn read<R>(&self, f: impl FnOnce(&DataBase) -> R) -> R;
+    fn write<R>(&self, f: impl FnOnce(&mut DataBase) -> R) -> R;
+}
+
+/// Every listing excludes the others as well as the writer, so readers
+/// queue up even when nothing is written.
+impl SharedStore for Mutex<DataBase> {
+    fn read<R>(&self, f: impl FnOnce(&DataBase) -> R) -> R {
+        f(&self.lock().unwrap())
+    }
+    fn write<R>(&self, f: impl FnOnce(&mut DataBase) -> R) -> R {
+        f(&mut self.lock().unwrap())
+    }
+}
+
+impl SharedStore for RwLock<DataBase> {
+    fn read<R>(&self, f: impl FnOnce(&DataBase) -> R) -> R {
+        f(&self.read().unwrap())
+    }
+    fn write<R>(&self, f: impl FnOnce(&mut DataBase) -> R) -> R {
+        f(&mut self.write().unwrap())
+    }
+}
+
+/// What `GET /users?birth_year=1990` does while holding the lock.
+fn list_users(database: &DataBase) -> String {
+    let filter = UserFilter {
+        birth_year: Some(1990),
+        ..Default::default()
+    };
+    let page = database
+        .page(&filter, &[], &PageRequest::default())
+        .unwrap();
+    serde_json::to_string(&page.items).unwrap()
+}
+
+/// Every reader lists users `iters` times while one writer changes a user
+/// after every fourth listing, and returns how long it took.
+fn mixed_load(store: &impl SharedStore, readers: usize, iters: u64) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..readers {
+            scope.spawn(|| {
+                for _ in 0..iters {
+                    black_box(store.read(list_users));
+                }
+            });
+        }
+        scope.spawn(|| {
+            for index in 0..iters * readers as u64 / 4 {
+                let id = (index % USERS as u64) as u32;
+                store.write(|database| {
+                    database
+                        .change_user(id, vec![UserEnum::Lastname(format!("changed{index}"))])
+                        .unwrap()
+                });
+            }
+        });
+    });
+    start.elapsed()
+}
+
+fn mixed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed_read_write");
+    for readers in READERS {
+        // One element is one listing, the writes come on top.
+        group.throughput(Throughput::Elements(readers as u64));
+
+        let store = Mutex::new(create_database());
+        group.bench_with_input(
+            BenchmarkId::new("mutex", readers),
+            &readers,
+            |b, &readers| b.iter_custom(|iters| mixed_load(&store, readers, iters)),
+        );
+        let store = RwLock::new(create_database());
+        group.bench_with_input(
+            BenchmarkId::new("rw_lock", readers),
+            &readers,
+            |b, &readers| b.iter_custom(|iters| mixed_load(&store, readers, iters)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mixed);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_api::{db_object::DataBase, Server, ServerCore, User, UserGroup};
+
+const CLIENTS: [usize; 3] = [4, 32, 256];
+/// How long a client waits after each response, like a user or a slow
+/// network would.
+const THINK_TIME: Duration = Duration::from_millis(1);
+
+fn create_database() -> DataBase {
+    DataBase::from_users(
+        (0..100)
+            .map(|id| User {
+                id,
+                name: format!("name{id}"),
+                lastname: format!("lastname{id}"),
+                birth_year: 1950 + (id % 60) as u16,
+                group: UserGroup::User,
+            })
+            .collect(),
+    )
+}
+
+fn read_response(reader: &mut BufReader<TcpStream>) {
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length: ") {
+            length = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+}
+
+/// Every client sends `iters` requests over a keep-alive connection of its
+/// own, and returns how long it took until all of them were answered.
+fn slow_clients(address: SocketAddr, clients: usize, iters: u64) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..clients {
+            scope.spawn(|| {
+                let stream = TcpStream::connect(address).unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                for _ in 0..iters {
+                    writer.write_all(b"GET /users/1 HTTP/1.1\r\n\r\n").unwrap();
+                    read_response(&mut reader);
+                    thread::sleep(THINK_TIME);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+/// The thread pool serves one connection per worker until it closes, the
+/// event loop serves every connection at once.
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("slow_clients");
+    group.sample_size(10);
+    for (name, core) in [
+        ("thread_pool", ServerCore::ThreadPool),
+        ("event_loop", ServerCore::EventLoop),
+    ] {
+        let server = Server::builder()
+            .store(Arc::new(RwLock::new(create_database())))
+            .core(core)
+            .start()
+            .unwrap();
+        for clients in CLIENTS {
+            // One element is one request.
+            group.throughput(Throughput::Elements(clients as u64));
+            group.bench_with_input(BenchmarkId::new(name, clients), &clients, |b, &clients| {
+                b.iter_custom(|iters| slow_clients(server.local_addr(), clients, iters))
+            });
+        }
+        server.shutdown();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load);
+criterion_main!(benches);
@@ -0,0 +1,138 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_api::{
+    db_object::{DataBase, UserEnum, UserFilter},
+    store::UserStore,
+    User, UserGroup,
+};
+
+const SIZES: [u32; 3] = [1_000, 100_000, 1_000_000];
+
+fn create_users(count: u32) -> Vec<User> {
+    (0..count)
+        .map(|id| User {
+            id,
+            name: format!("name{id}"),
+            lastname: format!("lastname{id}"),
+            birth_year: 1950 + (id % 60) as u16,
+            group: if id % 100 == 0 {
+                UserGroup::Admin
+            } else {
+                UserGroup::User
+            },
+        })
+        .collect()
+}
+
+/// How users were looked up when they were kept in a `Vec`: a linear scan
+/// for the position, against the `BTreeMap` lookup `DataBase` does now.
+fn find(users: &[User], id: u32) -> Option<usize> {
+    users.iter().position(|user| user.id == id)
+}
+
+fn get_one(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_one");
+    for size in SIZES {
+        let users = create_users(size);
+        let database = DataBase::from_users(users.clone());
+        let id = size * 3 / 4;
+
+        group.bench_with_input(BenchmarkId::new("vec_scan", size), &id, |b, &id| {
+            b.iter(|| black_box(&users[find(&users, black_box(id)).unwrap()]))
+        });
+        group.bench_with_input(BenchmarkId::new("btree_map", size), &id, |b, &id| {
+            b.iter(|| black_box(database.get_one(black_box(id)).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+fn change_user(c: &mut Criterion) {
+    let mut group = c.benchmark_group("change_user");
+    for size in SIZES {
+        let mut users = create_users(size);
+        let mut database = DataBase::from_users(users.clone());
+        let id = size * 3 / 4;
+
+        group.bench_with_input(BenchmarkId::new("vec_scan", size), &id, |b, &id| {
+            b.iter(|| {
+                let index = find(&users, black_box(id)).unwrap();
+                users[index].birth_year = 1990;
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("btree_map", size), &id, |b, &id| {
+            b.iter(|| {
+                database
+                    .change_user(black_box(id), vec![UserEnum::BirthYear(1990)])
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Removes a user and puts it back, so every iteration sees the same size.
+fn remove_and_add(c: &mut Criterion) {
+    let mut group = c.benchmark_group("remove_and_add");
+    for size in SIZES {
+        let mut users = create_users(size);
+        let mut database = DataBase::from_users(users.clone());
+        let id = size / 4;
+
+        group.bench_with_input(BenchmarkId::new("vec_scan", size), &id, |b, &id| {
+            b.iter(|| {
+                let user = users.remove(find(&users, black_box(id)).unwrap());
+                users.push(user);
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("btree_map", size), &id, |b, &id| {
+            b.iter(|| {
+                let user = database.get_one(black_box(id)).unwrap().clone();
+                database.remove_entry(id).unwrap();
+                database.add_entry(user, Some(id)).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn query(c: &mut Criterion) {
+    let filters = [
+        (
+            "group",
+            UserFilter {
+                group: Some(UserGroup::Admin),
+                ..Default::default()
+            },
+        ),
+        (
+            "birth_year",
+            UserFilter {
+                birth_year: Some(1990),
+                ..Default::default()
+            },
+        ),
+    ];
+    for (name, filter) in filters {
+        let mut group = c.benchmark_group(format!("query_{name}"));
+        for size in SIZES {
+            let users = create_users(size);
+            let database = DataBase::from_users(users.clone());
+
+            group.bench_with_input(BenchmarkId::new("scan", size), &filter, |b, filter| {
+                b.iter(|| {
+                    users
+                        .iter()
+                        .filter(|user| filter.matches(user))
+                        .collect::<Vec<_>>()
+                })
+            });
+            group.bench_with_input(BenchmarkId::new("indexed", size), &filter, |b, filter| {
+                b.iter(|| database.query(filter))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, get_one, change_user, remove_and_add, query);
+criterion_main!(benches);
@@ -0,0 +1,359 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{db_object::RecordMeta, error::ApiError, request::Headers, response::Response};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The `ETag` and `Last-Modified` of a representation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    pub fn for_user(meta: &RecordMeta) -> Self {
+        Self {
+            etag: format!("\"v{}\"", meta.version),
+            last_modified: meta.modified,
+        }
+    }
+
+    /// A listing changes whenever one of its users does, or when the set of
+    /// users (`total`) or the `query` that produced it differs.
+    pub fn for_listing(
+        metas: &[(u32, RecordMeta)],
+        total: usize,
+        query: &str,
+        last_modified: SystemTime,
+    ) -> Self {
+        let mut hasher = DefaultHasher::new();
+        for (id, meta) in metas {
+            (id, meta.version).hash(&mut hasher);
+        }
+        (total, query).hash(&mut hasher);
+        Self {
+            etag: format!("\"l{:016x}\"", hasher.finish()),
+            last_modified,
+        }
+    }
+
+    /// Evaluates `If-None-Match` and `If-Modified-Since`. As required by
+    /// RFC 9110 the date is ignored when `If-None-Match` is present.
+    pub fn not_modified(&self, headers: &Headers) -> bool {
+        if let Some(if_none_match) = headers.get("If-None-Match") {
+            return etag_matches(if_none_match, &self.etag);
+        }
+        headers
+            .get("If-Modified-Since")
+            .and_then(parse_http_date)
+            .is_some_and(|since| seconds(self.last_modified) <= seconds(since))
+    }
+
+    pub fn apply(&self, response: Response) -> Response {
+        response
+            .with_header("ETag", &self.etag)
+            .with_header("Last-Modified", &format_http_date(self.last_modified))
+    }
+
+    /// Builds the 304 answer or the full response, both carrying validators.
+    pub fn respond(&self, headers: &Headers, body: String) -> Response {
+        if self.not_modified(headers) {
+            self.apply(Response::new(304, String::new()))
+        } else {
+            self.apply(Response::new(200, body))
+        }
+    }
+}
+
+/// The `If-Match` condition of a write request, or `Absent` for
+/// `If-None-Match: *`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Precondition {
+    None,
+    Any,
+    Tags(Vec<String>),
+    Absent,
+}
+
+impl Precondition {
+    /// With `required` set, writes without `If-Match` are rejected with 428,
+    /// unless `If-None-Match: *` asks for the user not to exist.
+    pub fn from_headers(headers: &Headers, required: bool) -> Result<Self, ApiError> {
+        match (headers.get("If-Match"), headers.get("If-None-Match")) {
+            (None, Some(if_none_match)) if if_none_match.trim() == "*" => Ok(Self::Absent),
+            (if_match, _) => Self::parse(if_match, required),
+        }
+    }
+
+    /// Parses an `If-Match` value given outside of the headers.
+    pub fn parse(if_match: Option<&str>, required: bool) -> Result<Self, ApiError> {
+        match if_match.map(str::trim) {
+            None if required => Err(precondition_required()),
+            None => Ok(Self::None),
+            Some("*") => Ok(Self::Any),
+            Some(tags) => Ok(Self::Tags(
+                tags.split(',').map(|tag| tag.trim().to_string()).collect(),
+            )),
+        }
+    }
+
+    /// Strong comparison against the current `etag`, `None` when the record
+    /// does not exist. Must run under the same lock as the write it guards.
+    pub fn check(&self, etag: Option<&str>) -> Result<(), ApiError> {
+        let holds = match (self, etag) {
+            (Self::None, _) => true,
+            (Self::Absent, etag) => etag.is_none(),
+            (_, None) => false,
+            (Self::Any, Some(_)) => true,
+            (Self::Tags(tags), Some(etag)) => tags.iter().any(|tag| tag == etag),
+        };
+        if holds {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                412,
+                "precondition_failed",
+                "The user was modified since it was last read",
+            ))
+        }
+    }
+}
+
+/// Rejects an unconditional write to an existing record, for requests that
+/// could only tell whether `If-Match` is required once the record was found.
+pub fn require_for_existing(
+    precondition: &Precondition,
+    etag: Option<&str>,
+) -> Result<(), ApiError> {
+    match (precondition, etag) {
+        (Precondition::None, Some(_)) => Err(precondition_required()),
+        _ => Ok(()),
+    }
+}
+
+fn precondition_required() -> ApiError {
+    ApiError::new(
+        428,
+        "precondition_required",
+        "This request must be made conditional with If-Match",
+    )
+}
+
+/// Weak comparison of an `If-None-Match` list against `etag`.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = seconds(time);
+    let days = secs / 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses an IMF-fixdate, the only format HTTP/1.1 senders must generate.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.split(' ');
+    let (_, day, month, year, time, zone) = (
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+    );
+    if zone != "GMT" || parts.next().is_some() {
+        return None;
+    }
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, secs) = (time.next()??, time.next()??, time.next()??);
+    if day == 0 || day > 31 || hours > 23 || minutes > 59 || secs > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86_400 + hours * 3600 + minutes * 60 + secs;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Conversions between days since the Unix epoch and the proleptic Gregorian
+// calendar, after Howard Hinnant's `civil_from_days`/`days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &str, value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.insert(name, value);
+        headers
+    }
+
+    #[test]
+    fn test_http_date_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(
+            format_http_date(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let validators = Validators::for_user(&RecordMeta {
+            version: 3,
+            modified: UNIX_EPOCH,
+        });
+
+        assert!(validators.not_modified(&headers("If-None-Match", "\"v3\"")));
+        assert!(validators.not_modified(&headers("If-None-Match", "\"v1\", W/\"v3\"")));
+        assert!(validators.not_modified(&headers("If-None-Match", "*")));
+        assert!(!validators.not_modified(&headers("If-None-Match", "\"v2\"")));
+        assert!(!validators.not_modified(&Headers::new()));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let validators = Validators::for_user(&RecordMeta {
+            version: 3,
+            modified: UNIX_EPOCH + Duration::from_millis(784_111_777_500),
+        });
+
+        assert!(validators.not_modified(&headers(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        )));
+        assert!(!validators.not_modified(&headers(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 08:49:36 GMT"
+        )));
+
+        let mut both = headers("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT");
+        both.insert("If-None-Match", "\"v2\"");
+        assert!(!validators.not_modified(&both));
+    }
+
+    #[test]
+    fn test_if_match() {
+        let required = Precondition::from_headers(&Headers::new(), true).unwrap_err();
+        assert_eq!(required.status, 428);
+
+        let precondition = Precondition::from_headers(&Headers::new(), false).unwrap();
+        assert_eq!(precondition.check(None), Ok(()));
+
+        let precondition = Precondition::from_headers(&headers("If-Match", "*"), true).unwrap();
+        assert_eq!(precondition.check(Some("\"v1\"")), Ok(()));
+        assert_eq!(precondition.check(None).unwrap_err().status, 412);
+
+        let precondition =
+            Precondition::from_headers(&headers("If-Match", "\"v1\", \"v2\""), true).unwrap();
+        assert_eq!(precondition.check(Some("\"v2\"")), Ok(()));
+        assert_eq!(precondition.check(Some("\"v3\"")).unwrap_err().status, 412);
+
+        let weak = Precondition::from_headers(&headers("If-Match", "W/\"v1\""), true).unwrap();
+        assert_eq!(weak.check(Some("\"v1\"")).unwrap_err().status, 412);
+
+        let absent = Precondition::from_headers(&headers("If-None-Match", "*"), true).unwrap();
+        assert_eq!(absent, Precondition::Absent);
+        assert_eq!(absent.check(None), Ok(()));
+        assert_eq!(absent.check(Some("\"v1\"")).unwrap_err().status, 412);
+    }
+
+    #[test]
+    fn test_require_for_existing() {
+        let unconditional = Precondition::None;
+        assert_eq!(require_for_existing(&unconditional, None), Ok(()));
+        let error = require_for_existing(&unconditional, Some("\"v1\"")).unwrap_err();
+        assert_eq!(error.status, 428);
+
+        let any = Precondition::Any;
+        assert_eq!(require_for_existing(&any, Some("\"v1\"")), Ok(()));
+    }
+
+    #[test]
+    fn test_listing_etag() {
+        let meta = RecordMeta::default();
+        let listing = Validators::for_listing(&[(1, meta)], 1, "", UNIX_EPOCH);
+
+        assert_eq!(
+            listing,
+            Validators::for_listing(&[(1, meta)], 1, "", UNIX_EPOCH)
+        );
+        assert_ne!(
+            listing.etag,
+            Validators::for_listing(&[(2, meta)], 1, "", UNIX_EPOCH).etag
+        );
+        assert_ne!(
+            listing.etag,
+            Validators::for_listing(&[(1, meta)], 2, "", UNIX_EPOCH).etag
+        );
+        assert_ne!(
+            listing.etag,
+            Validators::for_listing(&[(1, meta)], 1, "limit=1", UNIX_EPOCH).etag
+        );
+    }
+}
@@ -1,11 +1,22 @@
-use std::collections::HashMap;
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::{db_object::UserEnum, Errors, User};
+use crate::{
+    db_object::{RecordMeta, UserEnum, UserFilter},
+    error::ApiError,
+    store::UserStore,
+    transaction::Operation,
+    User,
+};
 
-#[derive(Clone, Debug)]
+/// Records every call it receives. Reads are recorded too, so the calls are
+/// kept behind a lock.
+#[derive(Debug)]
 pub struct DataBaseMock {
     db: Vec<User>,
-    pub calls: Vec<MockCalls>,
+    calls: Mutex<Vec<MockCalls>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -14,33 +25,67 @@ pub enum MockCalls {
     RemoveEntry { id: u32 },
     ChangeUser { id: u32, data: Vec<UserEnum> },
     GetAll,
+    Query { filter: UserFilter },
     GetOne { id: u32 },
+    Commit { operations: Vec<Operation> },
 }
 impl DataBaseMock {
     pub fn new(db: Vec<User>) -> Self {
         Self {
             db,
-            calls: Vec::new(),
+            calls: Mutex::new(Vec::new()),
         }
     }
-    pub fn add_entry(&mut self, user: User, new_id: Option<u32>) -> u32 {
-        self.calls.push(MockCalls::AddEntry { user, new_id });
-        0
+    pub fn calls(&self) -> Vec<MockCalls> {
+        self.calls.lock().unwrap().clone()
     }
-    pub fn remove_entry(&mut self, id: u32) -> Result<usize, Errors> {
-        self.calls.push(MockCalls::RemoveEntry { id });
-        Ok(0)
+    fn record(&self, call: MockCalls) {
+        self.calls.lock().unwrap().push(call);
     }
-    pub fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<usize, Errors> {
-        self.calls.push(MockCalls::ChangeUser { id, data });
+}
+
+impl UserStore for DataBaseMock {
+    fn add_entry(&mut self, user: User, new_id: Option<u32>) -> Result<u32, ApiError> {
+        self.record(MockCalls::AddEntry { user, new_id });
         Ok(0)
     }
-    pub fn get_all(&mut self) -> &Vec<User> {
-        self.calls.push(MockCalls::GetAll);
-        &self.db
+    fn remove_entry(&mut self, id: u32) -> Result<(), ApiError> {
+        self.record(MockCalls::RemoveEntry { id });
+        Ok(())
+    }
+    fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<(), ApiError> {
+        self.record(MockCalls::ChangeUser { id, data });
+        Ok(())
+    }
+    fn get_all(&self) -> Result<Vec<User>, ApiError> {
+        self.record(MockCalls::GetAll);
+        Ok(self.db.clone())
+    }
+    fn query(&self, filter: &UserFilter) -> Result<Vec<User>, ApiError> {
+        self.record(MockCalls::Query {
+            filter: filter.clone(),
+        });
+        Ok(self.db.clone())
+    }
+    fn get_meta(&self, _id: u32) -> Result<Option<RecordMeta>, ApiError> {
+        Ok(Some(RecordMeta::default()))
+    }
+    fn last_modified(&self) -> Result<SystemTime, ApiError> {
+        Ok(UNIX_EPOCH)
+    }
+    fn get_one(&self, id: u32) -> Result<User, ApiError> {
+        self.record(MockCalls::GetOne { id });
+        Ok(self.db[0].clone())
     }
-    pub fn get_one(&mut self, id: u32) -> Result<&User, Errors> {
-        self.calls.push(MockCalls::GetOne { id });
-        Ok(&self.db[0])
+    fn commit(&mut self, operations: Vec<Operation>) -> Result<Vec<u32>, ApiError> {
+        let ids = operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Add { id, .. } => id.unwrap_or(0),
+                Operation::Change { id, .. } | Operation::Remove { id } => *id,
+            })
+            .collect();
+        self.record(MockCalls::Commit { operations });
+        Ok(ids)
     }
 }
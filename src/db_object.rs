@@ -1,51 +1,480 @@
-use crate::{Errors, User, UserGroup};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::RangeInclusive,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ApiError,
+    pagination::{Page, PageRequest},
+    store::UserStore,
+    transaction::Operation,
+    User, UserGroup,
+};
+
+/// Queries use an index only when it selects at most one in `SCAN_RATIO`
+/// users, otherwise scanning everything is faster.
+const SCAN_RATIO: usize = 32;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct DataBase {
-    pub db: Vec<User>,
+    /// Users keyed by id, so lookups are O(log n) and listings come out in
+    /// id order without sorting.
+    users: BTreeMap<u32, User>,
+    /// Secondary indexes, kept in sync by every write.
+    by_group: BTreeMap<UserGroup, BTreeSet<u32>>,
+    by_birth_year: BTreeMap<u16, BTreeSet<u32>>,
+    meta: HashMap<u32, RecordMeta>,
+    version: u64,
+    modified: SystemTime,
+    /// Next id handed out by `add_entry`. It only grows, so ids of deleted
+    /// users are never reused.
+    next_id: u32,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// What [`DataBase::undo`] needs to revert a batch of operations: every
+/// user as it was before each write, newest last, and the counters.
+#[derive(Debug)]
+pub(crate) struct Undo {
+    users: Vec<(u32, Option<(User, RecordMeta)>)>,
+    next_id: u32,
+    version: u64,
+    modified: SystemTime,
+}
+
+/// Bookkeeping kept for every stored user. Versions come from a single
+/// counter, so they never repeat across users or after a delete.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordMeta {
+    pub version: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum UserEnum {
     Name(String),
     Lastname(String),
     BirthYear(u16),
     Group(UserGroup),
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserField {
+    Id,
+    Name,
+    Lastname,
+    BirthYear,
+    Group,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortKey {
+    pub field: UserField,
+    pub descending: bool,
+}
+
+/// Where a user falls in a listing ordered by a list of [`SortKey`]s, ties
+/// are always broken by ascending id.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SortPosition {
+    pub values: Vec<FieldValue>,
+    pub id: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserFilter {
+    pub group: Option<UserGroup>,
+    pub birth_year: Option<u16>,
+    pub birth_year_gte: Option<u16>,
+    pub birth_year_lte: Option<u16>,
+    pub name_prefix: Option<String>,
+    pub lastname_prefix: Option<String>,
+}
+
+impl UserFilter {
+    pub fn matches(&self, user: &User) -> bool {
+        self.group.as_ref().is_none_or(|group| user.group == *group)
+            && self.birth_year.is_none_or(|year| user.birth_year == year)
+            && self
+                .birth_year_gte
+                .is_none_or(|year| user.birth_year >= year)
+            && self
+                .birth_year_lte
+                .is_none_or(|year| user.birth_year <= year)
+            && self
+                .name_prefix
+                .as_ref()
+                .is_none_or(|prefix| user.name.starts_with(prefix.as_str()))
+            && self
+                .lastname_prefix
+                .as_ref()
+                .is_none_or(|prefix| user.lastname.starts_with(prefix.as_str()))
+    }
+
+    /// The birth years allowed by the filter, `None` when it does not
+    /// restrict them. An empty range means nothing can match.
+    pub fn birth_year_range(&self) -> Option<RangeInclusive<u16>> {
+        if self.birth_year.is_none()
+            && self.birth_year_gte.is_none()
+            && self.birth_year_lte.is_none()
+        {
+            return None;
+        }
+        let low = self.birth_year.max(self.birth_year_gte).unwrap_or(u16::MIN);
+        let high = [self.birth_year, self.birth_year_lte]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(u16::MAX);
+        Some(low..=high)
+    }
+}
+
+impl UserField {
+    pub const ALL: [UserField; 5] = [
+        Self::Id,
+        Self::Name,
+        Self::Lastname,
+        Self::BirthYear,
+        Self::Group,
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name() == name)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Id => "id",
+            Self::Name => "name",
+            Self::Lastname => "lastname",
+            Self::BirthYear => "birth_year",
+            Self::Group => "group",
+        }
+    }
+    pub fn value(&self, user: &User) -> FieldValue {
+        match self {
+            Self::Id => FieldValue::Number(user.id.into()),
+            Self::Name => FieldValue::Text(user.name.clone()),
+            Self::Lastname => FieldValue::Text(user.lastname.clone()),
+            Self::BirthYear => FieldValue::Number(user.birth_year.into()),
+            Self::Group => FieldValue::Text(format!("{:?}", user.group)),
+        }
+    }
+}
+
+impl SortPosition {
+    pub fn of(user: &User, sort: &[SortKey]) -> Self {
+        Self {
+            values: sort.iter().map(|key| key.field.value(user)).collect(),
+            id: user.id,
+        }
+    }
+    pub fn compare(&self, other: &Self, sort: &[SortKey]) -> Ordering {
+        sort.iter()
+            .zip(self.values.iter().zip(&other.values))
+            .map(
+                |(key, (a, b))| {
+                    if key.descending {
+                        b.cmp(a)
+                    } else {
+                        a.cmp(b)
+                    }
+                },
+            )
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| self.id.cmp(&other.id))
+    }
+}
+
+pub fn sort_users(users: &mut Vec<&User>, sort: &[SortKey]) {
+    if sort.is_empty() {
+        users.sort_by_key(|user| user.id);
+        return;
+    }
+    let mut positions: Vec<_> = users
+        .drain(..)
+        .map(|user| (SortPosition::of(user, sort), user))
+        .collect();
+    positions.sort_by(|(a, _), (b, _)| a.compare(b, sort));
+    users.extend(positions.into_iter().map(|(_, user)| user));
+}
+
+impl Default for RecordMeta {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            modified: UNIX_EPOCH,
+        }
+    }
+}
+
+impl Default for DataBase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DataBase {
     pub fn new() -> Self {
-        Self { db: Vec::new() }
-    }
-    pub fn add_entry(&mut self, mut user: User, new_id: Option<u32>) -> u32 {
-        let last_user = self.db.last();
-        let id = new_id.unwrap_or(if let Some(last_user) = last_user {
-            last_user.id + 1
-        } else {
-            0
+        Self::from_users(Vec::new())
+    }
+    pub fn from_users(users: Vec<User>) -> Self {
+        let mut database = Self {
+            users: BTreeMap::new(),
+            by_group: BTreeMap::new(),
+            by_birth_year: BTreeMap::new(),
+            meta: HashMap::new(),
+            version: 0,
+            modified: SystemTime::now(),
+            next_id: 0,
+        };
+        for user in users {
+            let id = user.id;
+            database.next_id = database.next_id.max(id.saturating_add(1));
+            database.index(&user);
+            database.users.insert(id, user);
+            database.touch(id);
+        }
+        database
+    }
+    /// Rebuilds a store from a snapshot, keeping the id and version counters
+    /// where they were even if the users that last used them were deleted,
+    /// and every user's version, so ETags stay valid.
+    pub fn restore(users: Vec<(User, RecordMeta)>, next_id: u32, version: u64) -> Self {
+        let (users, metas): (Vec<_>, Vec<_>) = users
+            .into_iter()
+            .map(|(user, meta)| {
+                let id = user.id;
+                (user, (id, meta))
+            })
+            .unzip();
+        let mut database = Self::from_users(users);
+        database.next_id = database.next_id.max(next_id);
+        database.version = metas
+            .iter()
+            .map(|(_, meta)| meta.version)
+            .fold(version, u64::max);
+        database.meta = metas.into_iter().collect();
+        database
+    }
+    /// Every user with its bookkeeping, in id order.
+    pub fn entries(&self) -> Vec<(User, RecordMeta)> {
+        self.users
+            .iter()
+            .map(|(id, user)| (user.clone(), self.meta[id]))
+            .collect()
+    }
+    /// The id `add_entry` hands out next when none is given.
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+    /// The version the last write was given.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    /// The id `add_entry` would store a user under, without adding it. Fails
+    /// when `new_id` is taken or no id would be left after it.
+    pub(crate) fn assign_id(&self, new_id: Option<u32>) -> Result<u32, ApiError> {
+        let id = match new_id {
+            Some(id) if self.users.contains_key(&id) => return Err(ApiError::user_exists(id)),
+            Some(id) => id,
+            None => self.next_id,
+        };
+        id.checked_add(1)
+            .ok_or_else(|| ApiError::new(507, "ids_exhausted", "No more user ids are available"))?;
+        Ok(id)
+    }
+    pub fn get_one(&self, id: u32) -> Result<&User, ApiError> {
+        self.users
+            .get(&id)
+            .ok_or_else(|| ApiError::user_not_found(id))
+    }
+    pub fn get_all(&self) -> Vec<&User> {
+        self.users.values().collect()
+    }
+    /// Looks candidates up in the smaller of the group and birth year
+    /// indexes. Filters without either of them, or matching a large share of
+    /// all users, are answered by a scan, which is cheaper than one lookup
+    /// per candidate.
+    pub fn query(&self, filter: &UserFilter) -> Vec<&User> {
+        let by_group = filter
+            .group
+            .as_ref()
+            .map(|group| self.by_group.get(group).into_iter().collect::<Vec<_>>());
+        let by_birth_year = filter.birth_year_range().map(|years| {
+            if years.is_empty() {
+                return Vec::new();
+            }
+            self.by_birth_year
+                .range(years)
+                .map(|(_, ids)| ids)
+                .collect::<Vec<_>>()
         });
+        let count = |sets: &[&BTreeSet<u32>]| sets.iter().map(|ids| ids.len()).sum::<usize>();
+        let candidates = match (by_group, by_birth_year) {
+            (Some(group), Some(years)) if count(&group) < count(&years) => Some(group),
+            (_, Some(years)) => Some(years),
+            (group, None) => group,
+        };
+        let Some(candidates) =
+            candidates.filter(|candidates| count(candidates) * SCAN_RATIO <= self.users.len())
+        else {
+            return self
+                .users
+                .values()
+                .filter(|user| filter.matches(user))
+                .collect();
+        };
+
+        let mut ids: Vec<u32> = candidates.into_iter().flatten().copied().collect();
+        ids.sort_unstable();
+        ids.iter()
+            .filter_map(|id| self.users.get(id))
+            .filter(|user| filter.matches(user))
+            .collect()
+    }
+    pub fn get_meta(&self, id: u32) -> Option<RecordMeta> {
+        self.meta.get(&id).copied()
+    }
+    pub fn last_modified(&self) -> SystemTime {
+        self.modified
+    }
+    /// Applies `operations` in order. When one fails the others are undone
+    /// and the error points at it, otherwise the ids they wrote are returned
+    /// together with what reverts them.
+    pub(crate) fn apply_all(
+        &mut self,
+        operations: &[Operation],
+    ) -> Result<(Vec<u32>, Undo), ApiError> {
+        let mut undo = Undo {
+            users: Vec::new(),
+            next_id: self.next_id,
+            version: self.version,
+            modified: self.modified,
+        };
+        let mut ids = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.iter().enumerate() {
+            let id = match operation {
+                Operation::Add { id, .. } => id.unwrap_or(self.next_id),
+                Operation::Change { id, .. } | Operation::Remove { id } => *id,
+            };
+            let previous = self.users.get(&id).cloned().map(|user| {
+                let meta = self.get_meta(id).unwrap_or_default();
+                (user, meta)
+            });
+            let result = match operation {
+                Operation::Add { user, id } => self.add_entry(user.clone(), *id),
+                Operation::Change { id, data } => self.change_user(*id, data.clone()).map(|_| *id),
+                Operation::Remove { id } => self.remove_entry(*id).map(|_| *id),
+            };
+            match result {
+                Ok(id) => {
+                    undo.users.push((id, previous));
+                    ids.push(id);
+                }
+                Err(error) => {
+                    self.undo(undo);
+                    return Err(error.in_operation(index));
+                }
+            }
+        }
+        Ok((ids, undo))
+    }
+    /// Puts every user touched by [`DataBase::apply_all`] back.
+    pub(crate) fn undo(&mut self, undo: Undo) {
+        for (id, previous) in undo.users.into_iter().rev() {
+            if let Some(user) = self.users.remove(&id) {
+                self.unindex(&user);
+            }
+            self.meta.remove(&id);
+            if let Some((user, meta)) = previous {
+                self.index(&user);
+                self.users.insert(id, user);
+                self.meta.insert(id, meta);
+            }
+        }
+        self.next_id = undo.next_id;
+        self.version = undo.version;
+        self.modified = undo.modified;
+    }
+    fn index(&mut self, user: &User) {
+        self.by_group
+            .entry(user.group.clone())
+            .or_default()
+            .insert(user.id);
+        self.by_birth_year
+            .entry(user.birth_year)
+            .or_default()
+            .insert(user.id);
+    }
+    fn unindex(&mut self, user: &User) {
+        if let Some(ids) = self.by_group.get_mut(&user.group) {
+            ids.remove(&user.id);
+            if ids.is_empty() {
+                self.by_group.remove(&user.group);
+            }
+        }
+        if let Some(ids) = self.by_birth_year.get_mut(&user.birth_year) {
+            ids.remove(&user.id);
+            if ids.is_empty() {
+                self.by_birth_year.remove(&user.birth_year);
+            }
+        }
+    }
+    fn touch(&mut self, id: u32) {
+        self.version += 1;
+        self.modified = SystemTime::now();
+        self.meta.insert(
+            id,
+            RecordMeta {
+                version: self.version,
+                modified: self.modified,
+            },
+        );
+    }
+}
+
+/// The reads clone out of the borrowing inherent methods of the same name.
+impl UserStore for DataBase {
+    /// Stores `user` under `new_id`, or under the next free id when `None`.
+    /// An explicit id that is already taken is a conflict.
+    fn add_entry(&mut self, mut user: User, new_id: Option<u32>) -> Result<u32, ApiError> {
+        let id = self.assign_id(new_id)?;
+        self.next_id = self.next_id.max(id + 1);
         user.id = id;
 
-        self.db.push(user);
+        self.index(&user);
+        self.users.insert(id, user);
+        self.touch(id);
 
-        id
+        Ok(id)
     }
-    pub fn remove_entry(&mut self, id: u32) -> Result<usize, Errors> {
+    fn remove_entry(&mut self, id: u32) -> Result<(), ApiError> {
         let user = self
-            .db
-            .iter()
-            .position(|user| user.id == id)
-            .ok_or(Errors::UserError(400))?;
-        self.db.remove(user);
-        Ok(user)
-    }
-    pub fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<usize, Errors> {
-        let user_id = self
-            .db
-            .iter()
-            .position(|user| user.id == id)
-            .ok_or(Errors::UserError(400))?;
-
-        let user = self.db.get_mut(user_id).unwrap();
+            .users
+            .remove(&id)
+            .ok_or_else(|| ApiError::user_not_found(id))?;
+        self.unindex(&user);
+        self.meta.remove(&id);
+        self.modified = SystemTime::now();
+        Ok(())
+    }
+    fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<(), ApiError> {
+        let mut user = self
+            .users
+            .remove(&id)
+            .ok_or_else(|| ApiError::user_not_found(id))?;
+        self.unindex(&user);
 
         data.iter().for_each(|change_data| match change_data {
             UserEnum::Name(name) => user.name = name.to_owned(),
@@ -53,21 +482,42 @@ impl DataBase {
             UserEnum::BirthYear(birth_year) => user.birth_year = *birth_year,
             UserEnum::Group(group) => user.group = group.clone(),
         });
+        self.index(&user);
+        self.users.insert(id, user);
+        self.touch(id);
 
-        Ok(user_id)
+        Ok(())
     }
-
-    pub fn get_all(&self) -> &Vec<User> {
-        &self.db
+    fn get_one(&self, id: u32) -> Result<User, ApiError> {
+        self.get_one(id).cloned()
     }
-
-    pub fn get_one(&self, id: u32) -> Result<&User, Errors> {
-        let user_id = self
-            .db
-            .iter()
-            .position(|user| user.id == id)
-            .ok_or(Errors::UserError(400))?;
-        Ok(&self.db.get(user_id).unwrap())
+    fn get_all(&self) -> Result<Vec<User>, ApiError> {
+        Ok(self.get_all().into_iter().cloned().collect())
+    }
+    fn query(&self, filter: &UserFilter) -> Result<Vec<User>, ApiError> {
+        Ok(self.query(filter).into_iter().cloned().collect())
+    }
+    /// Sorts borrowed users and only clones the page.
+    fn page(
+        &self,
+        filter: &UserFilter,
+        sort: &[SortKey],
+        request: &PageRequest,
+    ) -> Result<Page<Vec<User>>, ApiError> {
+        let mut users = self.query(filter);
+        sort_users(&mut users, sort);
+        Ok(request
+            .paginate(&users, sort)
+            .map(|page| page.into_iter().cloned().collect()))
+    }
+    fn get_meta(&self, id: u32) -> Result<Option<RecordMeta>, ApiError> {
+        Ok(self.get_meta(id))
+    }
+    fn last_modified(&self) -> Result<SystemTime, ApiError> {
+        Ok(self.last_modified())
+    }
+    fn commit(&mut self, operations: Vec<Operation>) -> Result<Vec<u32>, ApiError> {
+        self.apply_all(&operations).map(|(ids, _)| ids)
     }
 }
 
@@ -94,7 +544,7 @@ mod tests {
     }
 
     fn create_database() -> DataBase {
-        DataBase { db: create_users() }
+        DataBase::from_users(create_users())
     }
 
     fn create_user(id: u32) -> User {
@@ -110,12 +560,38 @@ mod tests {
     #[test]
     fn test_add_entry() {
         let mut database = create_database();
-        database.add_entry(create_user(3), None);
+        database.add_entry(create_user(3), None).unwrap();
 
         let mut expected = create_users();
         expected.push(create_user(3));
 
-        assert_eq!(database.db, expected);
+        assert_eq!(database.get_all(), expected.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_ids_are_never_reused() {
+        let mut database = create_database();
+        database.remove_entry(2).unwrap();
+
+        assert_eq!(database.add_entry(create_user(0), None), Ok(3));
+        assert_eq!(database.add_entry(create_user(0), Some(10)), Ok(10));
+        assert_eq!(database.add_entry(create_user(0), Some(5)), Ok(5));
+        assert_eq!(database.add_entry(create_user(0), None), Ok(11));
+        assert_eq!(DataBase::new().add_entry(create_user(0), None), Ok(0));
+    }
+
+    #[test]
+    fn test_explicit_id_conflict() {
+        let mut database = create_database();
+
+        assert_eq!(
+            database.add_entry(create_user(0), Some(1)),
+            Err(ApiError::user_exists(1))
+        );
+        assert_eq!(
+            database.get_all(),
+            create_users().iter().collect::<Vec<_>>()
+        );
     }
 
     #[test]
@@ -126,7 +602,7 @@ mod tests {
         let mut expected = create_users();
         expected.remove(0);
 
-        assert_eq!(database.db, expected);
+        assert_eq!(database.get_all(), expected.iter().collect::<Vec<_>>());
     }
 
     #[test]
@@ -139,13 +615,16 @@ mod tests {
         ];
         database.change_user(1, change_data).unwrap();
 
-        assert_eq!(database.db[0], create_user(1));
+        assert_eq!(*database.get_all()[0], create_user(1));
     }
 
     #[test]
     fn test_get_all() {
         let database = create_database();
-        assert_eq!(*database.get_all(), create_users());
+        assert_eq!(
+            database.get_all(),
+            create_users().iter().collect::<Vec<_>>()
+        );
     }
 
     #[test]
@@ -161,9 +640,199 @@ mod tests {
         assert_eq!(*database.get_one(1).unwrap(), user);
     }
 
+    #[test]
+    fn test_query() {
+        let mut database = create_database();
+        database.add_entry(create_user(3), None).unwrap();
+        database
+            .change_user(3, vec![UserEnum::BirthYear(1990)])
+            .unwrap();
+
+        let filter = UserFilter {
+            group: Some(UserGroup::Admin),
+            ..Default::default()
+        };
+        assert_eq!(database.query(&filter), vec![&create_users()[0]]);
+
+        let filter = UserFilter {
+            birth_year_gte: Some(1995),
+            name_prefix: Some("Wo".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(database.query(&filter), vec![&create_users()[1]]);
+
+        let filter = UserFilter {
+            birth_year_lte: Some(1995),
+            lastname_prefix: Some("test".to_string()),
+            ..Default::default()
+        };
+        let ids: Vec<u32> = database.query(&filter).iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![3]);
+
+        let filter = UserFilter {
+            birth_year: Some(2000),
+            ..Default::default()
+        };
+        assert_eq!(database.query(&filter).len(), 2);
+    }
+
+    #[test]
+    fn test_indexes_follow_writes() {
+        let mut database = create_database();
+        let ids = |database: &DataBase, filter: UserFilter| -> Vec<u32> {
+            database.query(&filter).iter().map(|user| user.id).collect()
+        };
+        let premium = || UserFilter {
+            group: Some(UserGroup::Premium),
+            ..Default::default()
+        };
+        let born_in_90s = || UserFilter {
+            birth_year_gte: Some(1990),
+            birth_year_lte: Some(1999),
+            ..Default::default()
+        };
+
+        database.add_entry(create_user(0), Some(3)).unwrap();
+        database.add_entry(create_user(0), Some(4)).unwrap();
+        assert_eq!(ids(&database, premium()), vec![3, 4]);
+        assert_eq!(ids(&database, born_in_90s()), Vec::<u32>::new());
+
+        database
+            .change_user(
+                3,
+                vec![UserEnum::BirthYear(1995), UserEnum::Group(UserGroup::User)],
+            )
+            .unwrap();
+        assert_eq!(ids(&database, premium()), vec![4]);
+        assert_eq!(ids(&database, born_in_90s()), vec![3]);
+
+        database.remove_entry(3).unwrap();
+        database.remove_entry(4).unwrap();
+        assert_eq!(ids(&database, premium()), Vec::<u32>::new());
+        assert_eq!(ids(&database, born_in_90s()), Vec::<u32>::new());
+        assert!(database.by_group.values().all(|ids| !ids.is_empty()));
+        assert!(!database.by_birth_year.contains_key(&1995));
+
+        let filter = UserFilter {
+            group: Some(UserGroup::User),
+            birth_year: Some(2000),
+            birth_year_gte: Some(2001),
+            ..Default::default()
+        };
+        assert_eq!(ids(&database, filter), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_birth_year_range() {
+        let filter = UserFilter {
+            birth_year_gte: Some(1990),
+            birth_year_lte: Some(1999),
+            ..Default::default()
+        };
+        assert_eq!(filter.birth_year_range(), Some(1990..=1999));
+
+        let filter = UserFilter {
+            birth_year: Some(1995),
+            birth_year_lte: Some(1999),
+            ..Default::default()
+        };
+        assert_eq!(filter.birth_year_range(), Some(1995..=1995));
+        assert_eq!(UserFilter::default().birth_year_range(), None);
+    }
+
+    #[test]
+    fn test_sort_users() {
+        let mut database = create_database();
+        database.add_entry(create_user(3), None).unwrap();
+        database
+            .change_user(3, vec![UserEnum::BirthYear(1990)])
+            .unwrap();
+        let mut users: Vec<&User> = database.get_all().into_iter().rev().collect();
+
+        sort_users(&mut users, &[]);
+        let ids: Vec<u32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let sort = [
+            SortKey {
+                field: UserField::BirthYear,
+                descending: true,
+            },
+            SortKey {
+                field: UserField::Lastname,
+                descending: false,
+            },
+        ];
+        sort_users(&mut users, &sort);
+        let ids: Vec<u32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![2, 1, 3]);
+
+        let sort = [SortKey {
+            field: UserField::Group,
+            descending: false,
+        }];
+        sort_users(&mut users, &sort);
+        let ids: Vec<u32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_versions_change_on_every_write() {
+        let mut database = create_database();
+        let first = database.get_meta(1).unwrap();
+        let second = database.get_meta(2).unwrap();
+        assert_ne!(first.version, second.version);
+
+        database
+            .change_user(1, vec![UserEnum::Name("test".to_string())])
+            .unwrap();
+        let changed = database.get_meta(1).unwrap();
+        assert!(changed.version > second.version);
+        assert!(changed.modified >= first.modified);
+        assert_eq!(database.get_meta(2), Some(second));
+
+        database.remove_entry(1).unwrap();
+        assert_eq!(database.get_meta(1), None);
+        assert!(database.last_modified() >= changed.modified);
+
+        database.add_entry(create_user(1), Some(1)).unwrap();
+        assert!(database.get_meta(1).unwrap().version > changed.version);
+    }
+
+    #[test]
+    fn test_restore_keeps_versions() {
+        let mut database = create_database();
+        database.add_entry(create_user(3), None).unwrap();
+        database.remove_entry(3).unwrap();
+        database
+            .change_user(1, vec![UserEnum::Name("test".to_string())])
+            .unwrap();
+
+        let restored = DataBase::restore(database.entries(), database.next_id(), 42);
+        assert_eq!(restored.get_meta(1), database.get_meta(1));
+        assert_eq!(restored.get_meta(2), database.get_meta(2));
+        assert_eq!(restored.version(), 42);
+        assert_eq!(restored.get_all(), database.get_all());
+    }
+
     #[test]
     fn test_error_if_id_does_not_exist() {
         let database = create_database();
-        assert_eq!(database.get_one(0), Err(Errors::UserError(400)));
+        assert_eq!(database.get_one(0), Err(ApiError::user_not_found(0)));
+    }
+
+    #[test]
+    fn test_change_or_remove_missing_user() {
+        let mut database = create_database();
+
+        assert_eq!(
+            database.change_user(5, vec![UserEnum::Name("test".to_string())]),
+            Err(ApiError::user_not_found(5))
+        );
+        assert_eq!(database.remove_entry(5), Err(ApiError::user_not_found(5)));
+        assert_eq!(
+            database.get_all(),
+            create_users().iter().collect::<Vec<_>>()
+        );
     }
 }
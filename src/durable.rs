@@ -0,0 +1,519 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    db_object::{DataBase, RecordMeta, SortKey, UserEnum, UserFilter},
+    error::ApiError,
+    pagination::{Page, PageRequest},
+    snapshot::Snapshot,
+    store::UserStore,
+    transaction::Operation,
+    wal::{invalid_data, LogEntry, WriteAheadLog},
+    User,
+};
+
+/// When a [`DurableDataBase`] compacts its log on its own. Whichever limit
+/// is reached first triggers a compaction after the write that crossed it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactionPolicy {
+    pub max_records: usize,
+    pub max_bytes: u64,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            max_records: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A [`DataBase`] whose writes are recorded in a [`WriteAheadLog`].
+///
+/// Every write is checked against the users first (an added user also needs
+/// a free id with another one left after it), then logged and synced to disk
+/// and only then applied in memory, so a write that returned `Ok` survives a
+/// crash and a failed one changes nothing.
+///
+/// The log is kept short by compaction: the users are written to a
+/// [`Snapshot`] next to the log (`<log>.snapshot`) and the log starts over
+/// with a [`LogEntry::Checkpoint`] naming that snapshot.
+#[derive(Debug)]
+pub struct DurableDataBase {
+    database: DataBase,
+    log: WriteAheadLog,
+    snapshot_path: PathBuf,
+    /// Generation of the snapshot the log continues.
+    generation: u64,
+    /// Set while the log still belongs to an older snapshot, which happens
+    /// when resetting it failed after a snapshot was written.
+    stale_log: bool,
+    policy: CompactionPolicy,
+}
+
+impl DurableDataBase {
+    /// Opens the log at `path` and rebuilds the users from the latest
+    /// snapshot and the log entries written after it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_policy(path, CompactionPolicy::default())
+    }
+
+    pub fn open_with_policy(path: impl AsRef<Path>, policy: CompactionPolicy) -> io::Result<Self> {
+        let path = path.as_ref();
+        let snapshot_path = snapshot_path(path);
+        let (database, generation) = match Snapshot::read(&snapshot_path)? {
+            Some(snapshot) => (
+                DataBase::restore(snapshot.users, snapshot.next_id, snapshot.version),
+                snapshot.generation,
+            ),
+            None => (DataBase::new(), 0),
+        };
+        let (log, entries) = WriteAheadLog::open(path)?;
+        let mut durable = Self {
+            database,
+            log,
+            snapshot_path,
+            generation,
+            stale_log: false,
+            policy,
+        };
+
+        let mut entries = entries.into_iter().peekable();
+        let log_generation =
+            match entries.next_if(|entry| matches!(entry, LogEntry::Checkpoint { .. })) {
+                Some(LogEntry::Checkpoint { generation }) => generation,
+                _ => 0,
+            };
+        if log_generation > generation {
+            return Err(invalid_data("log continues a snapshot that is missing"));
+        }
+        if log_generation < generation {
+            // A crash after the snapshot was renamed into place but before
+            // the log was reset: everything in the log is in the snapshot.
+            durable.reset_log()?;
+            return Ok(durable);
+        }
+
+        for entry in entries {
+            apply(&mut durable.database, entry).map_err(|error| {
+                invalid_data(&format!("log does not replay: {}", error.message))
+            })?;
+        }
+        Ok(durable)
+    }
+
+    /// Writes all users to a new snapshot and empties the log.
+    ///
+    /// The snapshot is in place before the log is touched, and the log is
+    /// only trusted on open when its checkpoint matches the snapshot, so a
+    /// crash at any point loses nothing.
+    pub fn compact_log(&mut self) -> io::Result<()> {
+        let generation = self.generation + 1;
+        Snapshot {
+            generation,
+            next_id: self.database.next_id(),
+            version: self.database.version(),
+            users: self.database.entries(),
+        }
+        .write(&self.snapshot_path)?;
+        self.generation = generation;
+        self.stale_log = true;
+        self.reset_log()
+    }
+
+    fn reset_log(&mut self) -> io::Result<()> {
+        self.log.reset(&LogEntry::Checkpoint {
+            generation: self.generation,
+        })?;
+        self.stale_log = false;
+        Ok(())
+    }
+
+    /// Compacts once the log grew past the policy. The write that got it
+    /// there is already durable, so a failure here is only reported.
+    fn compact_if_needed(&mut self) {
+        if self.log.records() < self.policy.max_records && self.log.len() < self.policy.max_bytes {
+            return;
+        }
+        if let Err(error) = self.compact_log() {
+            eprintln!("Compacting the log failed: {error}");
+        }
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> Result<(), ApiError> {
+        // Entries appended to a stale log would be skipped on the next open.
+        let result = if self.stale_log {
+            self.reset_log()
+        } else {
+            Ok(())
+        };
+        result
+            .and_then(|_| self.log.append(entry))
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::InvalidInput => ApiError::new(
+                    413,
+                    "record_too_large",
+                    "The change is too large to be stored",
+                ),
+                _ => {
+                    eprintln!("Writing to the log failed: {error}");
+                    ApiError::storage()
+                }
+            })
+    }
+
+    fn require_user(&self, id: u32) -> Result<(), ApiError> {
+        self.database.get_one(id).map(|_| ())
+    }
+}
+
+fn snapshot_path(log_path: &Path) -> PathBuf {
+    let mut name = log_path.as_os_str().to_owned();
+    name.push(".snapshot");
+    PathBuf::from(name)
+}
+
+fn apply(database: &mut DataBase, entry: LogEntry) -> Result<(), ApiError> {
+    match entry {
+        LogEntry::Add { user } => {
+            let id = user.id;
+            database.add_entry(user, Some(id)).map(|_| ())
+        }
+        LogEntry::Change { id, data } => database.change_user(id, data),
+        LogEntry::Remove { id } => database.remove_entry(id),
+        LogEntry::Batch { entries } => entries
+            .into_iter()
+            .try_for_each(|entry| apply(database, entry)),
+        LogEntry::Checkpoint { .. } => Err(ApiError::new(
+            500,
+            "storage_error",
+            "Checkpoint in the middle of the log",
+        )),
+    }
+}
+
+impl UserStore for DurableDataBase {
+    fn add_entry(&mut self, mut user: User, new_id: Option<u32>) -> Result<u32, ApiError> {
+        user.id = self.database.assign_id(new_id)?;
+        self.append(&LogEntry::Add { user: user.clone() })?;
+        let id = self.database.add_entry(user.clone(), Some(user.id))?;
+        self.compact_if_needed();
+        Ok(id)
+    }
+    fn remove_entry(&mut self, id: u32) -> Result<(), ApiError> {
+        self.require_user(id)?;
+        self.append(&LogEntry::Remove { id })?;
+        self.database.remove_entry(id)?;
+        self.compact_if_needed();
+        Ok(())
+    }
+    fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<(), ApiError> {
+        self.require_user(id)?;
+        self.append(&LogEntry::Change {
+            id,
+            data: data.clone(),
+        })?;
+        self.database.change_user(id, data)?;
+        self.compact_if_needed();
+        Ok(())
+    }
+    fn get_one(&self, id: u32) -> Result<User, ApiError> {
+        UserStore::get_one(&self.database, id)
+    }
+    fn get_all(&self) -> Result<Vec<User>, ApiError> {
+        UserStore::get_all(&self.database)
+    }
+    fn query(&self, filter: &UserFilter) -> Result<Vec<User>, ApiError> {
+        UserStore::query(&self.database, filter)
+    }
+    fn page(
+        &self,
+        filter: &UserFilter,
+        sort: &[SortKey],
+        request: &PageRequest,
+    ) -> Result<Page<Vec<User>>, ApiError> {
+        self.database.page(filter, sort, request)
+    }
+    fn get_meta(&self, id: u32) -> Result<Option<RecordMeta>, ApiError> {
+        Ok(self.database.get_meta(id))
+    }
+    fn last_modified(&self) -> Result<SystemTime, ApiError> {
+        Ok(self.database.last_modified())
+    }
+    /// The operations are applied in memory first, which tells whether all
+    /// of them succeed, and then logged as a single record. A failed append
+    /// undoes them again.
+    fn commit(&mut self, operations: Vec<Operation>) -> Result<Vec<u32>, ApiError> {
+        let (ids, undo) = self.database.apply_all(&operations)?;
+        let entries = operations
+            .into_iter()
+            .zip(&ids)
+            .map(|(operation, &id)| match operation {
+                Operation::Add { mut user, .. } => {
+                    user.id = id;
+                    LogEntry::Add { user }
+                }
+                Operation::Change { id, data } => LogEntry::Change { id, data },
+                Operation::Remove { id } => LogEntry::Remove { id },
+            })
+            .collect();
+        if let Err(error) = self.append(&LogEntry::Batch { entries }) {
+            self.database.undo(undo);
+            return Err(error);
+        }
+        self.compact_if_needed();
+        Ok(ids)
+    }
+    fn compact(&mut self) -> Result<(), ApiError> {
+        self.compact_log().map_err(|error| {
+            eprintln!("Compacting the log failed: {error}");
+            ApiError::storage()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wal::tests::temp_path, UserGroup};
+
+    fn create_user(name: &str) -> User {
+        User {
+            id: 0,
+            name: name.to_string(),
+            lastname: "test1".to_string(),
+            birth_year: 2000,
+            group: UserGroup::User,
+        }
+    }
+
+    fn names(database: &DurableDataBase) -> Vec<(u32, String)> {
+        database
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|user| (user.id, user.name))
+            .collect()
+    }
+
+    #[test]
+    fn test_reopen_restores_users() {
+        let path = temp_path("durable_reopen");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(database.add_entry(create_user("a"), None), Ok(0));
+        assert_eq!(database.add_entry(create_user("b"), Some(7)), Ok(7));
+        database
+            .change_user(0, vec![UserEnum::Name("c".to_string())])
+            .unwrap();
+        database.remove_entry(7).unwrap();
+        let before = names(&database);
+        drop(database);
+
+        let mut database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database), before);
+        assert_eq!(names(&database), vec![(0, "c".to_string())]);
+        assert_eq!(database.add_entry(create_user("d"), None), Ok(8));
+    }
+
+    #[test]
+    fn test_rejected_writes_are_not_logged() {
+        let path = temp_path("durable_rejected");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        database.add_entry(create_user("a"), None).unwrap();
+        let len = database.log.len();
+
+        assert_eq!(
+            database.add_entry(create_user("b"), Some(0)),
+            Err(ApiError::user_exists(0))
+        );
+        assert_eq!(
+            database.change_user(3, vec![UserEnum::BirthYear(1990)]),
+            Err(ApiError::user_not_found(3))
+        );
+        assert_eq!(database.remove_entry(3), Err(ApiError::user_not_found(3)));
+        assert_eq!(database.log.len(), len);
+    }
+
+    #[test]
+    fn test_exhausted_ids_are_not_logged() {
+        let path = temp_path("durable_exhausted");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        database.add_entry(create_user("a"), None).unwrap();
+        let len = database.log.len();
+
+        let error = database
+            .add_entry(create_user("b"), Some(u32::MAX))
+            .unwrap_err();
+        assert_eq!(error.status, 507);
+        assert_eq!(database.log.len(), len);
+
+        drop(database);
+        let database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database), vec![(0, "a".to_string())]);
+    }
+
+    #[test]
+    fn test_oversized_users_are_not_logged() {
+        let path = temp_path("durable_oversized");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        database.add_entry(create_user("a"), None).unwrap();
+
+        let error = database
+            .add_entry(create_user(&"b".repeat(16 * 1024 * 1024)), None)
+            .unwrap_err();
+        assert_eq!(error.status, 413);
+        assert_eq!(names(&database), vec![(0, "a".to_string())]);
+
+        drop(database);
+        let database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database), vec![(0, "a".to_string())]);
+    }
+
+    #[test]
+    fn test_transactions_are_logged_as_one_record() {
+        let path = temp_path("durable_transaction");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        database.add_entry(create_user("a"), None).unwrap();
+        let len = database.log.len();
+
+        let failed = vec![Operation::Remove { id: 0 }, Operation::Remove { id: 0 }];
+        assert_eq!(database.commit(failed).unwrap_err().status, 404);
+        assert_eq!(database.log.len(), len);
+        assert_eq!(names(&database), vec![(0, "a".to_string())]);
+
+        let operations = vec![
+            Operation::Add {
+                user: create_user("b"),
+                id: None,
+            },
+            Operation::Change {
+                id: 0,
+                data: vec![UserEnum::Name("c".to_string())],
+            },
+        ];
+        assert_eq!(database.commit(operations), Ok(vec![1, 0]));
+        assert_eq!(database.log.records(), 2);
+        drop(database);
+
+        let database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(
+            names(&database),
+            vec![(0, "c".to_string()), (1, "b".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_compaction_keeps_users() {
+        let path = temp_path("durable_compact");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        for name in ["a", "b", "c"] {
+            database.add_entry(create_user(name), None).unwrap();
+        }
+        database.remove_entry(2).unwrap();
+        let len = database.log.len();
+
+        database.compact().unwrap();
+        assert!(database.log.len() < len);
+        assert_eq!(database.log.records(), 1);
+        database
+            .change_user(0, vec![UserEnum::Name("d".to_string())])
+            .unwrap();
+        let before = names(&database);
+        drop(database);
+
+        let mut database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database), before);
+        assert_eq!(database.add_entry(create_user("e"), None), Ok(3));
+    }
+
+    #[test]
+    fn test_compaction_keeps_versions() {
+        let path = temp_path("durable_versions");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        for name in ["a", "b"] {
+            database.add_entry(create_user(name), None).unwrap();
+        }
+        database
+            .change_user(1, vec![UserEnum::Name("c".to_string())])
+            .unwrap();
+        let first = database.get_meta(0).unwrap();
+        let changed = database.get_meta(1).unwrap();
+        database.compact().unwrap();
+        drop(database);
+
+        // A client still holding the ETag from before the change must not
+        // see it match again after a restart.
+        let mut database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(database.get_meta(0).unwrap(), first);
+        assert_eq!(database.get_meta(1).unwrap(), changed);
+        database
+            .change_user(0, vec![UserEnum::Name("d".to_string())])
+            .unwrap();
+        assert!(database.get_meta(0).unwrap().unwrap().version > changed.unwrap().version);
+    }
+
+    #[test]
+    fn test_crash_before_log_reset() {
+        let path = temp_path("durable_crash");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        database.add_entry(create_user("a"), None).unwrap();
+        database.add_entry(create_user("b"), None).unwrap();
+        let before = names(&database);
+
+        // The snapshot is in place, but the log was never reset.
+        Snapshot {
+            generation: 1,
+            next_id: 2,
+            version: database.database.version(),
+            users: database.database.entries(),
+        }
+        .write(&snapshot_path(&path))
+        .unwrap();
+        drop(database);
+
+        let mut database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database), before);
+        assert_eq!(database.log.records(), 1);
+        database.add_entry(create_user("c"), None).unwrap();
+        drop(database);
+
+        let database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database).len(), 3);
+    }
+
+    #[test]
+    fn test_missing_snapshot_is_an_error() {
+        let path = temp_path("durable_missing_snapshot");
+        let mut database = DurableDataBase::open(&path).unwrap();
+        database.add_entry(create_user("a"), None).unwrap();
+        database.compact().unwrap();
+        drop(database);
+        std::fs::remove_file(snapshot_path(&path)).unwrap();
+
+        let error = DurableDataBase::open(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_policy_triggers_compaction() {
+        let path = temp_path("durable_policy");
+        let policy = CompactionPolicy {
+            max_records: 3,
+            ..CompactionPolicy::default()
+        };
+        let mut database = DurableDataBase::open_with_policy(&path, policy).unwrap();
+        for name in ["a", "b", "c", "d", "e"] {
+            database.add_entry(create_user(name), None).unwrap();
+            assert!(database.log.records() < 3);
+        }
+        assert_eq!(database.generation, 2);
+        drop(database);
+
+        let database = DurableDataBase::open(&path).unwrap();
+        assert_eq!(names(&database).len(), 5);
+    }
+}
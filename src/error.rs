@@ -0,0 +1,146 @@
+use serde::Serialize;
+
+use crate::response::reason_phrase;
+
+/// An error reported to the client as an RFC 7807 problem document.
+/// `code` is a stable, machine readable identifier, `message` is meant for
+/// humans and `fields` points at the individual inputs that were rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub code: &'static str,
+    pub message: String,
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ProblemDetails<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'a str,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    code: &'a str,
+    #[serde(skip_serializing_if = "<[FieldError]>::is_empty")]
+    errors: &'a [FieldError],
+}
+
+impl ApiError {
+    pub fn new(status: u16, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(400, code, message)
+    }
+    /// Returned by every store when no user has the requested id.
+    pub fn user_not_found(id: u32) -> Self {
+        Self::new(404, "user_not_found", format!("User {id} does not exist"))
+    }
+    /// Returned when a user is created under an id that is already taken.
+    pub fn user_exists(id: u32) -> Self {
+        Self::new(409, "user_exists", format!("User {id} already exists"))
+    }
+    pub fn internal() -> Self {
+        Self::new(500, "internal_error", "Internal server error")
+    }
+    /// The store could not read or write its data, the cause is logged.
+    pub fn storage() -> Self {
+        Self::new(500, "storage_error", "The users could not be accessed")
+    }
+    /// A 400 for a single rejected input, e.g. an unknown group value.
+    pub fn invalid_field(field: &str, code: &'static str, message: impl Into<String>) -> Self {
+        Self::invalid_fields(vec![FieldError::new(field, code, message)])
+    }
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        Self {
+            fields,
+            ..Self::bad_request("invalid_input", "The request contains invalid values")
+        }
+    }
+    pub fn with_field(
+        mut self,
+        field: &str,
+        code: &'static str,
+        message: impl Into<String>,
+    ) -> Self {
+        self.fields.push(FieldError::new(field, code, message));
+        self
+    }
+
+    /// Points the error at operation `index` of a transaction.
+    pub fn in_operation(mut self, index: usize) -> Self {
+        let operation = format!("operations[{index}]");
+        if self.fields.is_empty() {
+            let message = self.message.clone();
+            let code = self.code;
+            return self.with_field(&operation, code, message);
+        }
+        for field in &mut self.fields {
+            field.field = format!("{operation}.{}", field.field);
+        }
+        self
+    }
+
+    pub fn to_problem_json(&self) -> String {
+        let problem = ProblemDetails {
+            problem_type: "about:blank",
+            title: reason_phrase(self.status),
+            status: self.status,
+            detail: &self.message,
+            code: self.code,
+            errors: &self.fields,
+        };
+        serde_json::to_string(&problem).unwrap_or_default()
+    }
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<FieldError> for ApiError {
+    fn from(error: FieldError) -> Self {
+        Self::invalid_fields(vec![error])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem_json() {
+        let error = ApiError::invalid_field("group", "unknown_value", "Unknown group 'owner'");
+
+        assert_eq!(
+            error.to_problem_json(),
+            r#"{"type":"about:blank","title":"Bad Request","status":400,"detail":"The request contains invalid values","code":"invalid_input","errors":[{"field":"group","code":"unknown_value","message":"Unknown group 'owner'"}]}"#
+        );
+    }
+
+    #[test]
+    fn test_problem_json_without_fields() {
+        assert_eq!(
+            ApiError::internal().to_problem_json(),
+            r#"{"type":"about:blank","title":"Internal Server Error","status":500,"detail":"Internal server error","code":"internal_error"}"#
+        );
+    }
+}
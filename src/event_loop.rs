@@ -0,0 +1,585 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use polling::{Event, Events, Poller};
+
+use crate::{
+    overloaded,
+    request::{ParseError, Request, MAX_BODY_SIZE, MAX_HEADERS_SIZE, MAX_REQUEST_LINE_SIZE},
+    response::Response,
+    route,
+    router::Router,
+    Job, OverloadPolicy, ServerConfig, Stop, ThreadPool,
+};
+
+/// Key of the listener, connections are numbered from 1.
+const LISTENER: usize = 0;
+/// How often connections are checked for the keep-alive timeout.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(50);
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+/// No complete request is larger, so a connection that buffered this much
+/// without one is broken.
+const MAX_BUFFERED: usize =
+    MAX_REQUEST_LINE_SIZE + MAX_HEADERS_SIZE + MAX_BODY_SIZE + READ_CHUNK_SIZE;
+
+/// State shared by the event loop, the workers running its requests and the
+/// server handle.
+struct Shared {
+    poller: Poller,
+    /// Responses the workers finished, waiting to be written.
+    completions: Mutex<Vec<Completion>>,
+    shutting_down: AtomicBool,
+}
+
+struct Completion {
+    key: usize,
+    response: Response,
+    keep_alive: bool,
+}
+
+/// Serves `listener` from a single thread that waits for every connection
+/// at once. Only complete requests go to the workers of `pool`, so slow or
+/// idle clients occupy no worker.
+pub(crate) fn start(
+    listener: TcpListener,
+    router: Arc<Router>,
+    config: ServerConfig,
+    overload: OverloadPolicy,
+    pool: ThreadPool,
+) -> io::Result<Stop> {
+    let shared = Arc::new(Shared {
+        poller: Poller::new()?,
+        completions: Mutex::new(Vec::new()),
+        shutting_down: AtomicBool::new(false),
+    });
+    listener.set_nonblocking(true)?;
+    // SAFETY: the listener is deleted from the poller before it is dropped.
+    unsafe { shared.poller.add(&listener, Event::readable(LISTENER))? };
+
+    let event_loop = EventLoop {
+        shared: Arc::clone(&shared),
+        listener: Some(listener),
+        connections: HashMap::new(),
+        next_key: LISTENER + 1,
+        router,
+        config,
+        overload,
+        pool,
+        held: None,
+        paused: Vec::new(),
+    };
+    let thread = thread::spawn(move || event_loop.run());
+
+    Ok(Box::new(move || {
+        shared.shutting_down.store(true, Ordering::SeqCst);
+        let _ = shared.poller.notify();
+        thread.join().ok()
+    }))
+}
+
+struct EventLoop {
+    shared: Arc<Shared>,
+    /// Dropped once the server shuts down.
+    listener: Option<TcpListener>,
+    connections: HashMap<usize, Connection>,
+    next_key: usize,
+    router: Arc<Router>,
+    config: ServerConfig,
+    overload: OverloadPolicy,
+    pool: ThreadPool,
+    /// A request the full queue of the pool could not take yet, see
+    /// [`OverloadPolicy::Block`]. While one is held no connection is read
+    /// and none is accepted, so clients wait in the kernel's buffers.
+    held: Option<Job>,
+    /// The listener and connections that became ready while a request was
+    /// held, in the order to serve them once it is queued.
+    paused: Vec<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Waiting for the rest of the next request.
+    Reading,
+    /// A worker runs the request.
+    Handling,
+    Writing,
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    /// Received bytes that are not part of a handled request yet.
+    input: Vec<u8>,
+    /// The request at the start of `input` once its headers arrived, with
+    /// where its body is, while the rest of the body is on its way.
+    head: Option<(Request, Range<usize>)>,
+    output: Vec<u8>,
+    written: usize,
+    close_after_writing: bool,
+    peer_closed: bool,
+    /// Last time the connection made progress, for the keep-alive timeout.
+    active_at: Instant,
+}
+
+/// What a connection needs next.
+enum Step {
+    Continue,
+    WaitReadable,
+    WaitWritable,
+    Dispatch(Request),
+    Close,
+}
+
+enum Parsed {
+    /// The request without its body, and where the body is in the input.
+    Head(Request, Range<usize>),
+    Incomplete,
+    Invalid(ParseError),
+}
+
+impl EventLoop {
+    fn run(mut self) -> ThreadPool {
+        let mut events = Events::new();
+        let mut swept_at = Instant::now();
+        let mut deadline = None;
+
+        loop {
+            events.clear();
+            if let Err(error) = self.shared.poller.wait(&mut events, Some(SWEEP_INTERVAL)) {
+                if error.kind() != io::ErrorKind::Interrupted {
+                    println!("Event loop failed: {error}");
+                    break;
+                }
+            }
+            for event in events.iter() {
+                if event.key == LISTENER {
+                    self.accept();
+                } else {
+                    self.drive(event.key);
+                }
+            }
+            self.complete();
+
+            if deadline.is_none() && self.shared.shutting_down.load(Ordering::SeqCst) {
+                deadline = Some(Instant::now() + self.config.shutdown_timeout);
+                self.stop_accepting();
+            }
+            if swept_at.elapsed() >= SWEEP_INTERVAL {
+                self.close_timed_out();
+                swept_at = Instant::now();
+            }
+            if deadline
+                .is_some_and(|deadline| self.connections.is_empty() || Instant::now() >= deadline)
+            {
+                break;
+            }
+        }
+
+        self.stop_accepting();
+        let keys: Vec<usize> = self.connections.keys().copied().collect();
+        for key in keys {
+            self.close(key);
+        }
+        self.pool
+    }
+
+    fn accept(&mut self) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+        if self.held.is_some() {
+            self.paused.push(LISTENER);
+            return;
+        }
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let key = self.next_key;
+                    self.next_key += 1;
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    // SAFETY: connections are deleted from the poller in
+                    // `close` before they are dropped.
+                    if unsafe { self.shared.poller.add(&stream, Event::readable(key)) }.is_err() {
+                        continue;
+                    }
+                    self.connections.insert(key, Connection::new(stream));
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                // Either no connection is left or accepting failed, which
+                // only concerns that one connection.
+                Err(_) => break,
+            }
+        }
+        let _ = self
+            .shared
+            .poller
+            .modify(listener, Event::readable(LISTENER));
+    }
+
+    /// Advances the connection `key` as far as it gets without blocking.
+    fn drive(&mut self, key: usize) {
+        loop {
+            let Some(connection) = self.connections.get_mut(&key) else {
+                return;
+            };
+            let state = connection.state;
+            let step = match state {
+                State::Reading if self.held.is_some() => return self.paused.push(key),
+                State::Handling => return,
+                // A bug hit by one client's bytes must cost only its own
+                // connection, not the thread serving all of them.
+                _ => panic::catch_unwind(AssertUnwindSafe(|| match state {
+                    State::Reading => connection.read_request(),
+                    _ => connection.write_response(),
+                }))
+                .unwrap_or_else(|_| {
+                    println!("Connection {key} panicked; closing it.");
+                    Step::Close
+                }),
+            };
+            let interest = match step {
+                Step::Continue => continue,
+                Step::WaitReadable => Event::readable(key),
+                Step::WaitWritable => Event::writable(key),
+                Step::Dispatch(request) => return self.dispatch(key, request),
+                Step::Close => return self.close(key),
+            };
+            if self
+                .shared
+                .poller
+                .modify(&connection.stream, interest)
+                .is_err()
+            {
+                self.close(key);
+            }
+            return;
+        }
+    }
+
+    fn dispatch(&mut self, key: usize, request: Request) {
+        let router = Arc::clone(&self.router);
+        let shared = Arc::clone(&self.shared);
+        let job: Job = Box::new(move || {
+            let response = route(&router, &request);
+            shared.completions.lock().unwrap().push(Completion {
+                key,
+                response,
+                keep_alive: request.keep_alive(),
+            });
+            let _ = shared.poller.notify();
+        });
+
+        match self.overload {
+            OverloadPolicy::Block => {
+                if let Err(job) = self.pool.queue.try_push(job) {
+                    self.held = Some(job);
+                }
+            }
+            OverloadPolicy::Reject { retry_after } => {
+                if self.pool.try_execute(job).is_err() {
+                    if let Some(connection) = self.connections.get_mut(&key) {
+                        connection.discard_input();
+                    }
+                    self.respond(key, overloaded(retry_after), false);
+                }
+            }
+        }
+    }
+
+    /// Queues the held request once there is room, and then serves what
+    /// became ready in the meantime.
+    fn release_held(&mut self) {
+        let Some(job) = self.held.take() else {
+            return;
+        };
+        if let Err(job) = self.pool.queue.try_push(job) {
+            self.held = Some(job);
+            return;
+        }
+        // Serving one of them may hold a request again, the rest then go
+        // back to `paused`.
+        for key in std::mem::take(&mut self.paused) {
+            if key == LISTENER {
+                self.accept();
+            } else {
+                self.drive(key);
+            }
+        }
+    }
+
+    /// Starts writing the responses the workers finished.
+    fn complete(&mut self) {
+        let completions = std::mem::take(&mut *self.shared.completions.lock().unwrap());
+        for completion in completions {
+            self.respond(completion.key, completion.response, completion.keep_alive);
+        }
+        self.release_held();
+    }
+
+    fn respond(&mut self, key: usize, response: Response, keep_alive: bool) {
+        let keep_alive = keep_alive && !self.shared.shutting_down.load(Ordering::SeqCst);
+        if let Some(connection) = self.connections.get_mut(&key) {
+            connection.start_response(&response, keep_alive);
+            self.drive(key);
+        }
+    }
+
+    /// Closes the listener and every connection waiting for a new request,
+    /// the others are closed after their current response.
+    fn stop_accepting(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            let _ = self.shared.poller.delete(&listener);
+        }
+        let idle: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_idle())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in idle {
+            self.close(key);
+        }
+    }
+
+    /// Closes connections that made no progress within the keep-alive
+    /// timeout. Requests with a worker are not limited.
+    fn close_timed_out(&mut self) {
+        let timeout = self.config.keep_alive_timeout;
+        let timed_out: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                connection.state != State::Handling && connection.active_at.elapsed() >= timeout
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in timed_out {
+            self.close(key);
+        }
+    }
+
+    fn close(&mut self, key: usize) {
+        if let Some(connection) = self.connections.remove(&key) {
+            let _ = self.shared.poller.delete(&connection.stream);
+        }
+    }
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            state: State::Reading,
+            input: Vec::new(),
+            head: None,
+            output: Vec::new(),
+            written: 0,
+            close_after_writing: false,
+            peer_closed: false,
+            active_at: Instant::now(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.state == State::Reading && self.input.is_empty()
+    }
+
+    /// Parses the headers once they are complete and then only waits until
+    /// the whole body is buffered.
+    fn read_request(&mut self) -> Step {
+        if self.receive().is_err() {
+            return Step::Close;
+        }
+        let (mut request, body) = match self.head.take() {
+            Some(head) => head,
+            None => match parse_head(&self.input) {
+                Parsed::Head(request, body) => (request, body),
+                Parsed::Incomplete => return self.wait_for_input(),
+                Parsed::Invalid(error) => {
+                    return match error.to_response() {
+                        Some(response) => {
+                            self.start_response(&response, false);
+                            Step::Continue
+                        }
+                        None => Step::Close,
+                    }
+                }
+            },
+        };
+        if self.input.len() < body.end {
+            self.head = Some((request, body));
+            return self.wait_for_input();
+        }
+        request.body = self.input[body.clone()].to_vec();
+        self.input.drain(..body.end);
+        self.state = State::Handling;
+        Step::Dispatch(request)
+    }
+
+    fn wait_for_input(&self) -> Step {
+        if self.peer_closed || self.input.len() >= MAX_BUFFERED {
+            Step::Close
+        } else {
+            Step::WaitReadable
+        }
+    }
+
+    /// Drops what arrived after a rejected request, like
+    /// `reject_overloaded` does before answering.
+    fn discard_input(&mut self) {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        while matches!((&self.stream).read(&mut chunk), Ok(read) if read > 0) {}
+        self.input.clear();
+    }
+
+    /// Reads what arrived so far.
+    fn receive(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        while !self.peer_closed && self.input.len() < MAX_BUFFERED {
+            match (&self.stream).read(&mut chunk) {
+                Ok(0) => self.peer_closed = true,
+                Ok(read) => {
+                    self.input.extend_from_slice(&chunk[..read]);
+                    self.active_at = Instant::now();
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    fn start_response(&mut self, response: &Response, keep_alive: bool) {
+        self.output.clear();
+        self.written = 0;
+        // Writing into a `Vec` cannot fail.
+        let _ = response.write_to(&mut self.output, keep_alive);
+        self.close_after_writing = !keep_alive;
+        self.state = State::Writing;
+    }
+
+    fn write_response(&mut self) -> Step {
+        while self.written < self.output.len() {
+            match (&self.stream).write(&self.output[self.written..]) {
+                Ok(0) => return Step::Close,
+                Ok(written) => {
+                    self.written += written;
+                    self.active_at = Instant::now();
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    return Step::WaitWritable
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return Step::Close,
+            }
+        }
+        if self.close_after_writing {
+            return Step::Close;
+        }
+        // Pipelined requests may already be buffered.
+        self.state = State::Reading;
+        self.active_at = Instant::now();
+        Step::Continue
+    }
+}
+
+/// Parses the request line and headers at the start of `input`, which may
+/// hold only part of them.
+fn parse_head(input: &[u8]) -> Parsed {
+    let mut rest = input;
+    match Request::read_head(&mut rest) {
+        Ok((request, content_length)) => {
+            let start = input.len() - rest.len();
+            Parsed::Head(request, start..start + content_length)
+        }
+        // Reading from a slice cannot fail, it can only end too early.
+        Err(ParseError::ConnectionClosed | ParseError::Io) => Parsed::Incomplete,
+        Err(error) => Parsed::Invalid(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_partial_heads() {
+        let raw = b"POST /users HTTP/1.1\r\nContent-Length: 4\r\n\r\ntestGET /users HTTP/1.1\r\n";
+        let head = b"POST /users HTTP/1.1\r\nContent-Length: 4\r\n\r\n".len();
+
+        for end in [0, 5, 22, 40, head - 1] {
+            assert!(
+                matches!(parse_head(&raw[..end]), Parsed::Incomplete),
+                "{end}"
+            );
+        }
+        for end in [head, raw.len()] {
+            match parse_head(&raw[..end]) {
+                Parsed::Head(request, body) => {
+                    assert_eq!(request.method, "POST");
+                    assert_eq!(body, head..head + 4);
+                }
+                _ => panic!("head not parsed"),
+            }
+        }
+        assert!(matches!(parse_head(&raw[head + 4..]), Parsed::Incomplete));
+    }
+
+    #[test]
+    fn test_parse_invalid_request() {
+        assert!(matches!(
+            parse_head(b"GET /users HTTP/2.0\r\n"),
+            Parsed::Invalid(ParseError::VersionNotSupported)
+        ));
+        let long = vec![b'a'; MAX_REQUEST_LINE_SIZE + 3];
+        assert!(matches!(
+            parse_head(&long),
+            Parsed::Invalid(ParseError::UriTooLong)
+        ));
+    }
+
+    /// Sends `bytes` to `connection` and reads the request once they arrived.
+    fn read_after(client: &mut TcpStream, connection: &mut Connection, bytes: &[u8]) -> Step {
+        client.write_all(bytes).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        connection.read_request()
+    }
+
+    #[test]
+    fn test_body_arriving_in_pieces() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut connection = Connection::new(stream);
+
+        let head = b"POST /users HTTP/1.1\r\nContent-Length: 8\r\n\r\ntest";
+        let step = read_after(&mut client, &mut connection, head);
+        assert!(matches!(step, Step::WaitReadable));
+        assert!(connection.head.is_some());
+        let step = read_after(&mut client, &mut connection, b"te");
+        assert!(matches!(step, Step::WaitReadable));
+
+        match read_after(&mut client, &mut connection, b"stGET") {
+            Step::Dispatch(request) => assert_eq!(request.body, b"testtest"),
+            _ => panic!("request not dispatched"),
+        }
+        assert_eq!(connection.input, b"GET");
+        assert!(connection.head.is_none());
+    }
+}
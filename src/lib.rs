@@ -1,129 +1,640 @@
 use std::{
-    collections::HashMap,
-    sync::{mpsc, Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 use std::{
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
 };
 
+pub mod conditional;
 pub mod db_mock;
 pub mod db_object;
-pub mod db_object_enum;
+pub mod durable;
+pub mod error;
+mod event_loop;
+pub mod pagination;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod snapshot;
+pub mod sqlite;
+pub mod store;
+pub mod transaction;
 mod utils;
-use db_object_enum::DataObjectEnum;
+pub mod wal;
+use conditional::Precondition;
+use error::ApiError;
+use request::Request;
+use response::Response;
+use router::Router;
+use store::UserStore;
 use utils::*;
 
 use serde::{Deserialize, Serialize};
 
-pub fn run_server(address: &str, db: Arc<Mutex<DataObjectEnum>>) {
-    let listener = TcpListener::bind(address).unwrap();
-    let pool = ThreadPool::new(4);
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub keep_alive_timeout: Duration,
+    /// Rejects `PATCH`, `DELETE` and a `PUT` over an existing user without
+    /// `If-Match` with 428. A `PUT` creating a user needs no condition.
+    pub require_if_match: bool,
+    /// How long [`ServerHandle::shutdown`] waits for requests in flight
+    /// before it closes their connections.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive_timeout: Duration::from_secs(5),
+            require_if_match: false,
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How often a connection waiting for its next request checks whether the
+/// server is shutting down.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Serves the users in `db` on `address`. Panics when it cannot bind, use
+/// [`Server::builder`] to handle that.
+pub fn run_server<S: UserStore + ?Sized + 'static>(
+    address: &str,
+    db: Arc<RwLock<S>>,
+) -> ServerHandle {
+    run_server_with_config(address, db, ServerConfig::default())
+}
+
+pub fn run_server_with_config<S: UserStore + ?Sized + 'static>(
+    address: &str,
+    db: Arc<RwLock<S>>,
+    config: ServerConfig,
+) -> ServerHandle {
+    Server::builder()
+        .address(address)
+        .store(db)
+        .config(config)
+        .start()
+        .unwrap()
+}
+
+/// Serves an arbitrary set of routes, used to mount resources besides users.
+pub fn run_router(address: &str, router: Router, config: ServerConfig) -> ServerHandle {
+    Server::builder()
+        .address(address)
+        .router(router)
+        .config(config)
+        .start()
+        .unwrap()
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let database = Arc::clone(&db);
+/// Entry point for configuring and starting a server.
+pub struct Server;
 
-        pool.execute(|| {
-            handle_connection(stream, database);
-        });
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
     }
 }
 
-fn handle_connection(mut stream: TcpStream, db: Arc<Mutex<DataObjectEnum>>) {
-    let mut buf_reader = BufReader::new(&stream);
-    let mut request = String::new();
+/// Builds the routes once the configuration is final.
+type Routes = Box<dyn FnOnce(&ServerConfig) -> Router>;
 
-    loop {
-        let mut line = String::new();
-        buf_reader.read_line(&mut line).unwrap();
-        if line == "\r\n" {
-            break;
-        }
-        request.push_str(&line);
-    }
-
-    let content_length = request
-        .lines()
-        .find(|line| line.starts_with("Content-Length:"))
-        .and_then(|line| line.split(" ").nth(1))
-        .and_then(|len| len.parse::<usize>().ok())
-        .unwrap_or(0);
-
-    let mut body = vec![0; content_length];
-    buf_reader.read_exact(&mut body).unwrap();
-    let data = String::from_utf8(body).unwrap();
-
-    let request_line = request.split("\r\n").next().unwrap();
-    let mut request_data = request_line.split(" ");
-    let method = request_data.next().unwrap();
-    let path = request_data.next().unwrap();
-    let controller = UserController::new(db);
-
-    let (code, contents) = match (method, path) {
-        ("GET", "/users") => (Some(200), controller.show_users()),
-        ("GET", path) if path.starts_with("/users/") => {
-            let id = path.trim_start_matches("/users/");
-            if let Ok(user_id) = id.parse::<u32>() {
-                (Some(200), controller.show_user(user_id))
-            } else {
-                (None, Err(Errors::UserError(400)))
-            }
+/// What happens to new work while every worker is busy and the job queue is
+/// full. Jobs are connections with [`ServerCore::ThreadPool`] and single
+/// requests with [`ServerCore::EventLoop`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// The job waits for room. The thread pool stops accepting, so later
+    /// connections wait in the listen backlog of the operating system. The
+    /// event loop holds the request back and neither reads nor accepts
+    /// until it is queued.
+    Block,
+    /// The request is answered with 503 and its connection closed.
+    /// `Retry-After` tells the client how long to wait, in whole seconds
+    /// rounded up.
+    Reject { retry_after: Duration },
+}
+
+/// How a server waits for its connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ServerCore {
+    /// Every connection occupies a worker thread until it closes, so as many
+    /// slow or idle clients as there are threads stall the server.
+    #[default]
+    ThreadPool,
+    /// One thread waits for all connections with epoll and hands complete
+    /// requests to the workers, so a handful of threads serves thousands of
+    /// connections.
+    EventLoop,
+}
+
+/// Configures a server before [`ServerBuilder::start`]. By default it binds
+/// a free port on the loopback interface and serves with 4 threads, and the
+/// acceptor waits while [`DEFAULT_QUEUE_CAPACITY`] connections are queued.
+pub struct ServerBuilder {
+    address: String,
+    threads: usize,
+    core: ServerCore,
+    queue_capacity: usize,
+    overload: OverloadPolicy,
+    config: ServerConfig,
+    routes: Option<Routes>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:0".to_string(),
+            threads: 4,
+            core: ServerCore::ThreadPool,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            overload: OverloadPolicy::Block,
+            config: ServerConfig::default(),
+            routes: None,
         }
-        ("POST", "/users") => {
-            println!("{}", data);
-            match serde_json::from_str::<HashMap<String, String>>(data.as_str()) {
-                Ok(user) => {
-                    let id = controller.add_user(user, None);
-                    (Some(201), id)
+    }
+}
+
+impl ServerBuilder {
+    /// Where to listen. Port 0 picks a free port, see
+    /// [`ServerHandle::local_addr`].
+    pub fn address(mut self, address: &str) -> Self {
+        self.address = address.to_string();
+        self
+    }
+
+    /// Number of worker threads serving connections.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn core(mut self, core: ServerCore) -> Self {
+        self.core = core;
+        self
+    }
+
+    /// Number of accepted connections that may wait for a worker.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.overload = policy;
+        self
+    }
+
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Serves the users API on top of `db`.
+    pub fn store<S: UserStore + ?Sized + 'static>(mut self, db: Arc<RwLock<S>>) -> Self {
+        self.routes = Some(Box::new(move |config| users_router_with_config(db, config)));
+        self
+    }
+
+    /// Serves `router` instead of the users API.
+    pub fn router(mut self, router: Router) -> Self {
+        self.routes = Some(Box::new(move |_| router));
+        self
+    }
+
+    /// Binds the address and starts serving. Returns once the server
+    /// listens, connections are served on background threads until
+    /// [`ServerHandle::shutdown`].
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let Some(routes) = self.routes else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the server needs a store or a router",
+            ));
+        };
+        if self.threads == 0 || self.queue_capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the server needs at least one thread and room in its queue",
+            ));
+        }
+        let listener = TcpListener::bind(&self.address)?;
+        let address = listener.local_addr()?;
+        let router = Arc::new(routes(&self.config));
+        let pool = ThreadPool::with_capacity(self.threads, self.queue_capacity);
+        let queue = Arc::clone(&pool.queue);
+        let stop = match self.core {
+            ServerCore::ThreadPool => {
+                serve_with_threads(listener, router, self.config, self.overload, pool)
+            }
+            ServerCore::EventLoop => {
+                event_loop::start(listener, router, self.config, self.overload, pool)?
+            }
+        };
+
+        Ok(ServerHandle {
+            address,
+            queue,
+            stop,
+        })
+    }
+}
+
+/// Stops a running server core and hands back its pool once no connection
+/// is left, `None` when the thread owning the pool died and dropped it.
+type Stop = Box<dyn FnOnce() -> Option<ThreadPool> + Send>;
+
+/// Accepts connections on a thread of its own and serves each of them on a
+/// worker until it closes.
+fn serve_with_threads(
+    listener: TcpListener,
+    router: Arc<Router>,
+    config: ServerConfig,
+    overload: OverloadPolicy,
+    pool: ThreadPool,
+) -> Stop {
+    let address = listener.local_addr().ok();
+    let connections = Arc::new(Connections::default());
+    let shutdown_timeout = config.shutdown_timeout;
+
+    let accepted = Arc::clone(&connections);
+    let acceptor = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if accepted.is_shutting_down() {
+                break;
+            }
+            // A failed accept only concerns that one connection.
+            let Ok(stream) = stream else {
+                continue;
+            };
+            // Kept to answer with 503 should the queue be full.
+            let reply = match overload {
+                OverloadPolicy::Block => None,
+                OverloadPolicy::Reject { retry_after } => match stream.try_clone() {
+                    Ok(reply) => Some((reply, retry_after)),
+                    Err(_) => continue,
+                },
+            };
+            let Some(guard) = Connections::register(&accepted, &stream) else {
+                continue;
+            };
+            let router = Arc::clone(&router);
+            let config = config.clone();
+            let job = move || {
+                handle_connection(stream, router, config, &guard.connections);
+            };
+
+            match reply {
+                None => pool.execute(job),
+                Some((reply, retry_after)) => {
+                    if pool.try_execute(job).is_err() {
+                        reject_overloaded(reply, retry_after);
+                    }
                 }
-                Err(_) => (None, Err(Errors::UserError(400))),
             }
         }
-        ("PATCH", path) if path.starts_with("/users/") => {
-            let id = path.trim_start_matches("/users/");
-            if let Ok(user_id) = id.parse::<u32>() {
-                match serde_json::from_str::<HashMap<String, String>>(data.as_str()) {
-                    Ok(user) => (Some(204), controller.change_user_data(user_id, user)),
-                    Err(_) => (None, Err(Errors::UserError(400))),
+        pool
+    });
+
+    Box::new(move || {
+        connections.shutting_down.store(true, Ordering::SeqCst);
+        // `accept` only returns for a new connection, so one is made to wake it.
+        if let Some(address) = address {
+            let _ = TcpStream::connect(wake_address(address));
+        }
+        let pool = acceptor.join().ok();
+
+        connections.drain(shutdown_timeout);
+        pool
+    })
+}
+
+/// A running server. Dropping the handle leaves the server running.
+pub struct ServerHandle {
+    address: SocketAddr,
+    queue: Arc<JobQueue>,
+    stop: Stop,
+}
+
+impl ServerHandle {
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Statistics of the queue of connections waiting for a worker.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Stops accepting connections and lets the requests in flight finish.
+    ///
+    /// Connections waiting for their next request are closed right away,
+    /// busy ones after their current response. Connections still open after
+    /// [`ServerConfig::shutdown_timeout`] are closed forcibly. Returns once
+    /// every worker has exited.
+    pub fn shutdown(self) {
+        let pool = (self.stop)();
+        drop(pool);
+    }
+}
+
+/// Answers a connection there is no room for with 503, without reading its
+/// request. What already arrived of the request is discarded first, closing
+/// with unread data would reset the connection before the client sees the
+/// response.
+fn reject_overloaded(stream: TcpStream, retry_after: Duration) {
+    if stream.set_nonblocking(true).is_ok() {
+        let mut buffer = [0; 4096];
+        while matches!((&stream).read(&mut buffer), Ok(read) if read > 0) {}
+    }
+    if stream.set_nonblocking(false).is_err() {
+        return;
+    }
+    let _ = overloaded(retry_after).write_to(&mut &stream, false);
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// The 503 for a request there is no room for.
+fn overloaded(retry_after: Duration) -> Response {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Response::from(ApiError::new(
+        503,
+        "overloaded",
+        "The server is too busy to take the request",
+    ))
+    .with_header("Retry-After", &seconds.to_string())
+}
+
+/// Where to connect to reach a listener bound to `address`.
+fn wake_address(address: SocketAddr) -> SocketAddr {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, address.port())
+}
+
+/// The open connections of a server, so that shutting down can wait for
+/// them and close those that outlive the deadline.
+#[derive(Default)]
+struct Connections {
+    shutting_down: AtomicBool,
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, TcpStream>>,
+    closed: Condvar,
+}
+
+/// Keeps a connection registered until the worker serving it is done.
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    fn register(connections: &Arc<Self>, stream: &TcpStream) -> Option<ConnectionGuard> {
+        let stream = stream.try_clone().ok()?;
+        let id = connections.next_id.fetch_add(1, Ordering::Relaxed);
+        connections.open.lock().unwrap().insert(id, stream);
+        Some(ConnectionGuard {
+            connections: Arc::clone(connections),
+            id,
+        })
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to `timeout` for every connection to close, then shuts down
+    /// the sockets of the remaining ones.
+    fn drain(&self, timeout: Duration) {
+        let open = self.open.lock().unwrap();
+        let (open, _) = self
+            .closed
+            .wait_timeout_while(open, timeout, |open| !open.is_empty())
+            .unwrap();
+        for stream in open.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.remove(&self.id);
+        if open.is_empty() {
+            self.connections.closed.notify_all();
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    router: Arc<Router>,
+    config: ServerConfig,
+    connections: &Connections,
+) {
+    // The reader lives for the whole connection so that pipelined requests
+    // already buffered after the current one are not lost.
+    let mut buf_reader = BufReader::new(&stream);
+    let mut writer = &stream;
+
+    loop {
+        if !wait_for_request(&mut buf_reader, &config, connections) {
+            return;
+        }
+        let request = match Request::read_from(&mut buf_reader) {
+            Ok(request) => request,
+            Err(error) => {
+                // After a malformed request the stream position is unknown, so
+                // the connection is always closed.
+                if let Some(response) = error.to_response() {
+                    let _ = response.write_to(&mut writer, false);
                 }
-            } else {
-                (None, Err(Errors::UserError(400)))
+                return;
             }
+        };
+
+        let response = route(&router, &request);
+        // While shutting down every response closes its connection.
+        let keep_alive = request.keep_alive() && !connections.is_shutting_down();
+
+        if response.write_to(&mut writer, keep_alive).is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Runs the handler of `request`. A panicking handler must not take the
+/// connection or the worker down with it, it is answered with 500.
+fn route(router: &Router, request: &Request) -> Response {
+    panic::catch_unwind(AssertUnwindSafe(|| router.handle(request))).unwrap_or_else(|_| {
+        println!("Handler for {} {} panicked", request.method, request.path());
+        Response::from(ApiError::internal())
+    })
+}
+
+/// Waits until the next request starts to arrive. Gives up when the
+/// connection stays idle for the keep-alive timeout, or when the server
+/// shuts down in the meantime.
+fn wait_for_request(
+    reader: &mut BufReader<&TcpStream>,
+    config: &ServerConfig,
+    connections: &Connections,
+) -> bool {
+    let stream = *reader.get_ref();
+    let idle_since = Instant::now();
+    let arrived = loop {
+        let remaining = config
+            .keep_alive_timeout
+            .saturating_sub(idle_since.elapsed());
+        if remaining.is_zero()
+            || stream
+                .set_read_timeout(Some(remaining.min(IDLE_POLL_INTERVAL)))
+                .is_err()
+        {
+            break false;
         }
-        ("DELETE", path) if path.starts_with("/users/") => {
-            let id = path.trim_start_matches("/users/");
-            if let Ok(user_id) = id.parse::<u32>() {
-                (Some(204), controller.delete_user(user_id))
-            } else {
-                (None, Err(Errors::UserError(400)))
+        match reader.fill_buf() {
+            Ok(buffered) => break !buffered.is_empty(),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                if connections.is_shutting_down() {
+                    break false;
+                }
             }
+            Err(_) => break false,
         }
-        _ => (None, Err(Errors::UserError(404))),
     };
+    // A request that started to arrive must be complete within the timeout.
+    arrived
+        && stream
+            .set_read_timeout(Some(config.keep_alive_timeout))
+            .is_ok()
+}
 
-    let (status_line, contents) = match contents {
-        Ok(data) => (format!("HTTP/1.1 {}", code.unwrap()), data),
-        Err(Errors::ServerError(code)) => (
-            format!("HTTP/1.1 {}", code),
-            "Internal serve error".to_string(),
-        ),
-        Err(Errors::UserError(code)) => (
-            format!("HTTP/1.1 {}", code),
-            match code {
-                400 => "Invalid input".to_string(),
-                404 => "Not found".to_string(),
-                _ => "User error".to_string(),
-            },
-        ),
-    };
-    let length = contents.len();
+pub fn users_router<S: UserStore + ?Sized + 'static>(db: Arc<RwLock<S>>) -> Router {
+    users_router_with_config(db, &ServerConfig::default())
+}
+
+pub fn users_router_with_config<S: UserStore + ?Sized + 'static>(
+    db: Arc<RwLock<S>>,
+    config: &ServerConfig,
+) -> Router {
+    let mut router = Router::new();
+    let require_if_match = config.require_if_match;
+
+    let database = Arc::clone(&db);
+    router.get("/users", move |request, _| {
+        let controller = UserController::new(Arc::clone(&database));
+        let query = request.query();
+        let (page, validators) = controller.show_users(&query)?;
+
+        let mut response = validators
+            .respond(&request.headers, page.items.clone())
+            .with_header("X-Total-Count", &page.total.to_string());
+        if let Some(link) = page.link_header(request.path(), &query) {
+            response = response.with_header("Link", &link);
+        }
+        Ok(response)
+    });
+
+    let database = Arc::clone(&db);
+    router.get("/users/{id}", move |request, params| {
+        let controller = UserController::new(Arc::clone(&database));
+        let (user, validators) = controller.show_user(params.get("id")?, &request.query())?;
+        Ok(validators.respond(&request.headers, user))
+    });
+
+    let database = Arc::clone(&db);
+    router.post("/users", move |request, _| {
+        let controller = UserController::new(Arc::clone(&database));
+        let mut user = parse_body(request)?;
+        let new_id = take_id(&mut user)?;
+        Ok(Response::new(201, controller.add_user(user, new_id)?))
+    });
+
+    let database = Arc::clone(&db);
+    router.put("/users/{id}", move |request, params| {
+        let controller = UserController::new(Arc::clone(&database));
+        let user_id = params.get("id")?;
+        // Whether If-Match is required depends on the user existing, which
+        // the controller checks under the write lock.
+        let precondition = Precondition::from_headers(&request.headers, false)?;
+        let user = parse_body(request)?;
+        let (created, user) =
+            controller.replace_user(user_id, user, &precondition, require_if_match)?;
+        let code = if created { 201 } else { 200 };
+        Ok(Response::new(code, user))
+    });
+
+    let database = Arc::clone(&db);
+    router.patch("/users/{id}", move |request, params| {
+        let controller = UserController::new(Arc::clone(&database));
+        let user_id = params.get("id")?;
+        let precondition = Precondition::from_headers(&request.headers, require_if_match)?;
+        let user = parse_body(request)?;
+        Ok(Response::new(
+            204,
+            controller.change_user_data(user_id, user, &precondition)?,
+        ))
+    });
+
+    let database = Arc::clone(&db);
+    router.delete("/users/{id}", move |request, params| {
+        let controller = UserController::new(Arc::clone(&database));
+        let precondition = Precondition::from_headers(&request.headers, require_if_match)?;
+        Ok(Response::new(
+            204,
+            controller.delete_user(params.get("id")?, &precondition)?,
+        ))
+    });
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    let database = Arc::clone(&db);
+    router.post("/transactions", move |request, _| {
+        let controller = UserController::new(Arc::clone(&database));
+        Ok(Response::new(
+            200,
+            controller.commit_transaction(&request.body, require_if_match)?,
+        ))
+    });
 
-    stream.write_all(response.as_bytes()).unwrap();
+    let database = Arc::clone(&db);
+    router.post("/admin/compact", move |_, _| {
+        let controller = UserController::new(Arc::clone(&database));
+        controller.compact()?;
+        Ok(Response::new(204, String::new()))
+    });
+
+    router
+}
+
+fn parse_body(request: &Request) -> Result<HashMap<String, String>, ApiError> {
+    serde_json::from_slice(&request.body).map_err(|_| {
+        ApiError::bad_request(
+            "invalid_json",
+            "Request body must be a JSON object with string values",
+        )
+    })
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -135,7 +646,7 @@ pub struct User {
     pub group: UserGroup,
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 pub enum UserGroup {
     User,
     Premium,
@@ -144,76 +655,232 @@ pub enum UserGroup {
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    queue: Arc<JobQueue>,
 }
 
+/// Queue capacity of [`ThreadPool::new`] and of servers by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    /// Replaced by the new thread when a job panics on the old one.
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+/// Counters of the job queue of a [`ThreadPool`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub capacity: usize,
+    /// Jobs waiting for a worker right now.
+    pub queued: usize,
+    /// Most jobs that waited at the same time.
+    pub peak: usize,
+    /// Jobs handed to a worker so far.
+    pub started: u64,
+    /// Jobs turned away by [`ThreadPool::try_execute`] because the queue
+    /// was full.
+    pub rejected: u64,
+}
+
+/// Jobs waiting for a worker, at most `capacity` of them.
+struct JobQueue {
+    capacity: usize,
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: VecDeque<Job>,
+    /// Set when the pool is dropped, workers exit once the queue is empty.
+    closed: bool,
+    peak: usize,
+    started: u64,
+    rejected: u64,
+}
+
+impl JobQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(QueueState::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    fn push(&self, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        while state.jobs.len() >= self.capacity {
+            state = self.not_full.wait(state).unwrap();
+        }
+        self.enqueue(state, job);
+    }
+
+    fn try_push<F: FnOnce() + Send + 'static>(&self, f: F) -> Result<(), F> {
+        let state = self.state.lock().unwrap();
+        if state.jobs.len() >= self.capacity {
+            return Err(f);
+        }
+        self.enqueue(state, Box::new(f));
+        Ok(())
+    }
+
+    fn enqueue(&self, mut state: MutexGuard<'_, QueueState>, job: Job) {
+        state.jobs.push_back(job);
+        state.peak = state.peak.max(state.jobs.len());
+        self.not_empty.notify_one();
+    }
+
+    /// The next job, `None` once the queue is closed and empty.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                state.started += 1;
+                self.not_full.notify_one();
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            capacity: self.capacity,
+            queued: state.jobs.len(),
+            peak: state.peak,
+            started: state.started,
+            rejected: state.rejected,
+        }
+    }
 }
 
 impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::with_capacity(size, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// A pool of `size` workers where at most `capacity` jobs wait for one.
+    pub fn with_capacity(size: usize, capacity: usize) -> ThreadPool {
         assert!(size > 0);
+        assert!(capacity > 0);
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let queue = Arc::new(JobQueue::new(capacity));
 
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&queue)));
         }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-        }
+        ThreadPool { workers, queue }
     }
+    /// Queues `f`, waiting for room while the queue is full.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        self.queue.push(Box::new(f));
+    }
+    /// Queues `f` unless the queue is full, then `f` is handed back.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.try_push(f).inspect_err(|_| {
+            self.queue.state.lock().unwrap().rejected += 1;
+        })
+    }
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.queue.close();
         for worker in &mut self.workers {
             println!("Shutting down worker {}", worker.id);
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            // A worker that panicked meanwhile left its replacement behind.
+            loop {
+                let thread = worker.thread.lock().unwrap().take();
+                match thread {
+                    Some(thread) => {
+                        let _ = thread.join();
+                    }
+                    None => break,
+                }
             }
         }
     }
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    fn new(id: usize, queue: Arc<JobQueue>) -> Worker {
+        let slot = Arc::new(Mutex::new(None));
+        let thread = Worker::spawn(id, queue, Arc::clone(&slot));
+        *slot.lock().unwrap() = Some(thread);
+        Worker { id, thread: slot }
+    }
 
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
+    fn spawn(
+        id: usize,
+        queue: Arc<JobQueue>,
+        slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let _respawn = Respawn {
+                id,
+                queue: Arc::clone(&queue),
+                slot,
+            };
+            loop {
+                // The job runs after the queue is unlocked, so it cannot
+                // poison the lock.
+                match queue.pop() {
+                    Some(job) => {
+                        println!("Worker {id} got a job; executing.");
 
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
+                        job();
+                    }
+                    None => {
+                        println!("Worker {id} disconnected; shutting down.");
+                        break;
+                    }
                 }
             }
-        });
-        Worker {
-            id,
-            thread: Some(thread),
+        })
+    }
+}
+
+/// Starts a new thread for its worker when the current one dies from a
+/// panicking job, so the pool keeps its size.
+struct Respawn {
+    id: usize,
+    queue: Arc<JobQueue>,
+    slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+impl Drop for Respawn {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
         }
+        println!("Worker {} panicked; respawning.", self.id);
+        let thread = Worker::spawn(self.id, Arc::clone(&self.queue), Arc::clone(&self.slot));
+        *self.slot.lock().unwrap_or_else(PoisonError::into_inner) = Some(thread);
     }
 }
@@ -1,11 +1,62 @@
-use rust_api::{db_object::DataBase, run_server};
-use std::sync::{Arc, Mutex};
+use rust_api::{
+    db_object::DataBase, durable::DurableDataBase, sqlite::SqliteStore, store::UserStore, Server,
+    ServerCore, ServerHandle,
+};
+use std::sync::{mpsc, Arc, RwLock};
 
+const ADDRESS: &str = "127.0.0.1:7878";
+
+/// Keeps the users in memory only, unless an argument names where to store
+/// them: `sqlite:<path>` for a SQLite database, any other path for a
+/// write-ahead log. `--event-loop` serves connections from an event loop
+/// instead of a thread each.
+///
+/// SIGINT and SIGTERM shut the server down gracefully.
 fn main() {
-    let db_object = DataBase::new();
-    let db = Arc::new(Mutex::new(
-        rust_api::db_object_enum::DataObjectEnum::DataBase(db_object),
-    ));
+    let (sender, signals) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = sender.send(());
+    })
+    .expect("Cannot install the signal handler");
+
+    let mut arguments: Vec<String> = std::env::args().skip(1).collect();
+    let core = match arguments
+        .iter()
+        .position(|argument| argument == "--event-loop")
+    {
+        Some(index) => {
+            arguments.remove(index);
+            ServerCore::EventLoop
+        }
+        None => ServerCore::ThreadPool,
+    };
+
+    let server = match arguments.first() {
+        Some(argument) => match argument.strip_prefix("sqlite:") {
+            Some(path) => {
+                let db = SqliteStore::open(path)
+                    .unwrap_or_else(|error| panic!("Cannot open the database at {path}: {error}"));
+                serve(db, core)
+            }
+            None => {
+                let db = DurableDataBase::open(argument)
+                    .unwrap_or_else(|error| panic!("Cannot open the log at {argument}: {error}"));
+                serve(db, core)
+            }
+        },
+        None => serve(DataBase::new(), core),
+    };
+
+    signals.recv().expect("The signal handler is gone");
+    println!("Shutting down");
+    server.shutdown();
+}
 
-    run_server("127.0.0.1:7878", db);
+fn serve<S: UserStore + 'static>(db: S, core: ServerCore) -> ServerHandle {
+    Server::builder()
+        .address(ADDRESS)
+        .store(Arc::new(RwLock::new(db)))
+        .core(core)
+        .start()
+        .unwrap_or_else(|error| panic!("Cannot listen on {ADDRESS}: {error}"))
 }
@@ -0,0 +1,379 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db_object::{SortKey, SortPosition},
+    error::ApiError,
+    request::Query,
+    User,
+};
+
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
+pub const MAX_PAGE_LIMIT: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PageStart {
+    /// The first page, following pages are linked with cursors.
+    First,
+    Offset(usize),
+    /// Cursor pointing right after the given position.
+    After(SortPosition),
+    /// Cursor for a page ending at the given position.
+    UpTo(SortPosition),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRequest {
+    pub limit: usize,
+    pub start: PageStart,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: T,
+    pub total: usize,
+    pub next: Option<PageRequest>,
+    pub prev: Option<PageRequest>,
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_PAGE_LIMIT,
+            start: PageStart::First,
+        }
+    }
+}
+
+impl PageRequest {
+    /// Reads `limit` together with either `offset` or an opaque `cursor`. A
+    /// cursor is only valid for the sort order it was created with.
+    pub fn from_query(query: &Query, sort: &[SortKey]) -> Result<Self, ApiError> {
+        let limit = match query.get("limit") {
+            Some(limit) => limit
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                .ok_or_else(|| {
+                    ApiError::invalid_field(
+                        "limit",
+                        "out_of_range",
+                        format!("limit must be a number between 1 and {MAX_PAGE_LIMIT}"),
+                    )
+                })?,
+            None => DEFAULT_PAGE_LIMIT,
+        };
+        let start = match (query.get("offset"), query.get("cursor")) {
+            (Some(_), Some(_)) => {
+                return Err(ApiError::invalid_field(
+                    "cursor",
+                    "conflict",
+                    "offset and cursor cannot be combined",
+                ))
+            }
+            (Some(offset), None) => PageStart::Offset(offset.parse().map_err(|_| {
+                ApiError::invalid_field("offset", "not_a_number", "offset must be a number")
+            })?),
+            (None, Some(cursor)) => match decode_cursor(cursor) {
+                Some(PageStart::After(position)) if position.values.len() == sort.len() => {
+                    PageStart::After(position)
+                }
+                Some(PageStart::UpTo(position)) if position.values.len() == sort.len() => {
+                    PageStart::UpTo(position)
+                }
+                _ => {
+                    return Err(ApiError::invalid_field(
+                        "cursor",
+                        "invalid_cursor",
+                        "cursor is malformed or belongs to a different sort order",
+                    ))
+                }
+            },
+            (None, None) => PageStart::First,
+        };
+        Ok(Self { limit, start })
+    }
+
+    /// Writes the page parameters into `query`, replacing previous ones.
+    pub fn write_query(&self, query: &mut Query) {
+        query.remove("offset");
+        query.remove("cursor");
+        query.set("limit", &self.limit.to_string());
+        match &self.start {
+            PageStart::First => {}
+            PageStart::Offset(offset) => query.set("offset", &offset.to_string()),
+            _ => query.set("cursor", &encode_cursor(&self.start)),
+        }
+    }
+
+    /// Slices a page out of `users`, which must be ordered by `sort`. Cursors
+    /// are resolved by position, so they stay valid when users are removed.
+    pub fn paginate<'a>(&self, users: &[&'a User], sort: &[SortKey]) -> Page<Vec<&'a User>> {
+        let total = users.len();
+        let not_after = |position: &SortPosition| {
+            users.partition_point(|user| {
+                SortPosition::of(user, sort).compare(position, sort) != Ordering::Greater
+            })
+        };
+        let (start, end) = match &self.start {
+            PageStart::First => (0, self.limit.min(total)),
+            PageStart::Offset(offset) => {
+                let start = (*offset).min(total);
+                (start, (start + self.limit).min(total))
+            }
+            PageStart::After(position) => {
+                let start = not_after(position);
+                (start, (start + self.limit).min(total))
+            }
+            PageStart::UpTo(position) => {
+                let end = not_after(position);
+                (end.saturating_sub(self.limit), end)
+            }
+        };
+
+        let cursor_based = !matches!(self.start, PageStart::Offset(_));
+        let next = (end < total).then(|| PageRequest {
+            limit: self.limit,
+            start: if cursor_based {
+                PageStart::After(SortPosition::of(users[end - 1], sort))
+            } else {
+                PageStart::Offset(end)
+            },
+        });
+        let prev = (start > 0).then(|| PageRequest {
+            limit: self.limit,
+            start: if cursor_based {
+                PageStart::UpTo(SortPosition::of(users[start - 1], sort))
+            } else {
+                PageStart::Offset(start.saturating_sub(self.limit))
+            },
+        });
+
+        Page {
+            items: users[start..end].to_vec(),
+            total,
+            next,
+            prev,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Page<U> {
+        Page {
+            items: f(self.items),
+            total: self.total,
+            next: self.next,
+            prev: self.prev,
+        }
+    }
+
+    /// Builds the `Link` header value, keeping every other parameter of the
+    /// original query.
+    pub fn link_header(&self, path: &str, query: &Query) -> Option<String> {
+        let links: Vec<String> = [("next", &self.next), ("prev", &self.prev)]
+            .into_iter()
+            .filter_map(|(rel, page)| {
+                let mut query = query.clone();
+                page.as_ref()?.write_query(&mut query);
+                Some(format!("<{path}?{query}>; rel=\"{rel}\""))
+            })
+            .collect();
+        (!links.is_empty()).then(|| links.join(", "))
+    }
+}
+
+fn encode_cursor(start: &PageStart) -> String {
+    let cursor = serde_json::to_string(start).unwrap_or_default();
+    cursor.bytes().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<PageStart> {
+    if !cursor.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(cursor.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db_object::{FieldValue, UserField},
+        UserGroup,
+    };
+
+    fn create_users() -> Vec<User> {
+        [(1, 2000), (2, 1990), (4, 2000), (7, 1980), (9, 1990)]
+            .into_iter()
+            .map(|(id, birth_year)| User {
+                id,
+                name: "test".to_string(),
+                lastname: "test1".to_string(),
+                birth_year,
+                group: UserGroup::User,
+            })
+            .collect()
+    }
+
+    fn after_id(id: u32) -> PageStart {
+        PageStart::After(SortPosition {
+            values: Vec::new(),
+            id,
+        })
+    }
+
+    fn ids(page: &Page<Vec<&User>>) -> Vec<u32> {
+        page.items.iter().map(|user| user.id).collect()
+    }
+
+    #[test]
+    fn test_offset_pages() {
+        let users = create_users();
+        let users: Vec<&User> = users.iter().collect();
+        let request = PageRequest::from_query(&Query::parse("limit=2&offset=2"), &[]).unwrap();
+        let page = request.paginate(&users, &[]);
+
+        assert_eq!(ids(&page), vec![4, 7]);
+        assert_eq!(page.total, 5);
+        assert_eq!(page.next.unwrap().start, PageStart::Offset(4));
+        assert_eq!(page.prev.unwrap().start, PageStart::Offset(0));
+    }
+
+    #[test]
+    fn test_cursor_pages() {
+        let users = create_users();
+        let users: Vec<&User> = users.iter().collect();
+        let request = PageRequest {
+            limit: 2,
+            start: after_id(2),
+        };
+        let page = request.paginate(&users, &[]);
+        assert_eq!(ids(&page), vec![4, 7]);
+
+        let next = page.next.clone().unwrap();
+        assert_eq!(next.start, after_id(7));
+        assert_eq!(ids(&next.paginate(&users, &[])), vec![9]);
+
+        let prev = page.prev.clone().unwrap();
+        assert_eq!(
+            prev.start,
+            PageStart::UpTo(SortPosition {
+                values: Vec::new(),
+                id: 2
+            })
+        );
+        assert_eq!(ids(&prev.paginate(&users, &[])), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_cursor_pages_with_sort() {
+        let sort = [SortKey {
+            field: UserField::BirthYear,
+            descending: true,
+        }];
+        let users = create_users();
+        let mut users: Vec<&User> = users.iter().collect();
+        crate::db_object::sort_users(&mut users, &sort);
+
+        let page = PageRequest {
+            limit: 3,
+            start: PageStart::Offset(0),
+        }
+        .paginate(&users, &sort);
+        assert_eq!(ids(&page), vec![1, 4, 2]);
+
+        let next = PageRequest {
+            limit: 3,
+            start: PageStart::After(SortPosition::of(users[2], &sort)),
+        };
+        let users: Vec<&User> = users.into_iter().filter(|user| user.id != 2).collect();
+        assert_eq!(ids(&next.paginate(&users, &sort)), vec![9, 7]);
+    }
+
+    #[test]
+    fn test_cursor_survives_deleted_user() {
+        let users = create_users();
+        let users: Vec<&User> = users.iter().filter(|user| user.id != 4).collect();
+        let request = PageRequest {
+            limit: 2,
+            start: after_id(4),
+        };
+
+        assert_eq!(ids(&request.paginate(&users, &[])), vec![7, 9]);
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let sort = [SortKey {
+            field: UserField::Name,
+            descending: false,
+        }];
+        let start = PageStart::After(SortPosition {
+            values: vec![FieldValue::Text("Jan".to_string())],
+            id: 42,
+        });
+        let mut query = Query::default();
+        PageRequest {
+            limit: 3,
+            start: start.clone(),
+        }
+        .write_query(&mut query);
+
+        let request = PageRequest::from_query(&query, &sort).unwrap();
+        assert_eq!(request.limit, 3);
+        assert_eq!(request.start, start);
+        assert_eq!(
+            PageRequest::from_query(&query, &[]).map_err(|error| error.fields[0].code),
+            Err("invalid_cursor")
+        );
+    }
+
+    #[test]
+    fn test_invalid_page_parameters() {
+        let mut cursor = Query::default();
+        PageRequest {
+            limit: 1,
+            start: after_id(1),
+        }
+        .write_query(&mut cursor);
+        let cursor = cursor.get("cursor").unwrap().to_string();
+
+        for query in [
+            "limit=0".to_string(),
+            "limit=abc".to_string(),
+            "limit=1001".to_string(),
+            "offset=-1".to_string(),
+            "cursor=zz".to_string(),
+            "cursor=616263".to_string(),
+            format!("offset=1&cursor={cursor}"),
+        ] {
+            assert_eq!(
+                PageRequest::from_query(&Query::parse(&query), &[]).map_err(|error| error.status),
+                Err(400),
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_link_header() {
+        let users = create_users();
+        let users: Vec<&User> = users.iter().collect();
+        let query = Query::parse("limit=2&offset=2&group=user");
+        let page = PageRequest::from_query(&query, &[])
+            .unwrap()
+            .paginate(&users, &[]);
+
+        assert_eq!(
+            page.link_header("/users", &query).unwrap(),
+            "</users?group=user&limit=2&offset=4>; rel=\"next\", \
+             </users?group=user&limit=2&offset=0>; rel=\"prev\""
+        );
+    }
+}
@@ -0,0 +1,562 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, Read},
+};
+
+use crate::{error::ApiError, response::Response};
+
+pub const MAX_REQUEST_LINE_SIZE: usize = 8 * 1024;
+pub const MAX_HEADERS_SIZE: usize = 16 * 1024;
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    map: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    values: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The peer closed the connection (or went idle) before sending a request.
+    ConnectionClosed,
+    /// The connection failed in the middle of a request.
+    Io,
+    BadRequest(&'static str),
+    PayloadTooLarge,
+    UriTooLong,
+    HeaderFieldsTooLarge,
+    VersionNotSupported,
+}
+
+enum LineError {
+    Eof,
+    TooLong,
+    InvalidUtf8,
+    Io,
+}
+
+impl Version {
+    fn parse(version: &str) -> Result<Self, ParseError> {
+        match version {
+            "HTTP/1.1" => Ok(Self::Http11),
+            "HTTP/1.0" => Ok(Self::Http10),
+            _ => {
+                let (major, minor) = version
+                    .strip_prefix("HTTP/")
+                    .and_then(|number| number.split_once('.'))
+                    .ok_or(ParseError::BadRequest("Malformed HTTP version"))?;
+                if major.parse::<u8>().is_ok() && minor.parse::<u8>().is_ok() {
+                    Err(ParseError::VersionNotSupported)
+                } else {
+                    Err(ParseError::BadRequest("Malformed HTTP version"))
+                }
+            }
+        }
+    }
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Repeated headers are folded into a single comma separated value.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.map
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.map.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    }
+}
+
+impl Query {
+    /// Parses an `application/x-www-form-urlencoded` query string. When a key
+    /// is repeated the last value wins.
+    pub fn parse(query: &str) -> Self {
+        let values = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(key), percent_decode(value))
+            })
+            .collect();
+        Self { values }
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+    pub fn remove(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+}
+
+/// Encodes the query back into a string, with keys in sorted order.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pairs: Vec<_> = self.values.iter().collect();
+        pairs.sort();
+        let pairs: Vec<String> = pairs
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+            .collect();
+        write!(f, "{}", pairs.join("&"))
+    }
+}
+
+impl Request {
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+    pub fn query(&self) -> Query {
+        self.target
+            .split_once('?')
+            .map(|(_, query)| Query::parse(query))
+            .unwrap_or_default()
+    }
+
+    /// Reads the next request from the connection. Empty lines in front of
+    /// the request line are skipped as allowed by RFC 9112.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Self, ParseError> {
+        let (mut request, content_length) = Self::read_head(reader)?;
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).map_err(|_| ParseError::Io)?;
+        request.body = body;
+        Ok(request)
+    }
+
+    /// Reads the request line and headers, and returns the request without
+    /// its body together with the length of the body that follows.
+    pub(crate) fn read_head<R: BufRead>(reader: &mut R) -> Result<(Self, usize), ParseError> {
+        let request_line = loop {
+            let line = match read_line(reader, MAX_REQUEST_LINE_SIZE) {
+                Ok(line) => line,
+                Err(LineError::Eof) | Err(LineError::Io) => {
+                    return Err(ParseError::ConnectionClosed)
+                }
+                Err(LineError::TooLong) => return Err(ParseError::UriTooLong),
+                Err(LineError::InvalidUtf8) => {
+                    return Err(ParseError::BadRequest("Malformed request line"))
+                }
+            };
+            if !line.is_empty() {
+                break line;
+            }
+        };
+
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+                (method, target, version)
+            }
+            _ => return Err(ParseError::BadRequest("Malformed request line")),
+        };
+        if method.is_empty() || !method.bytes().all(|byte| byte.is_ascii_uppercase()) {
+            return Err(ParseError::BadRequest("Malformed method"));
+        }
+        if !target.starts_with('/') {
+            return Err(ParseError::BadRequest("Malformed request target"));
+        }
+        let version = Version::parse(version)?;
+
+        let mut headers = Headers::new();
+        // Header lines with their CRLF, the empty line ending them is free.
+        let mut headers_size = 0;
+        loop {
+            let line = match read_line(reader, MAX_HEADERS_SIZE.saturating_sub(headers_size)) {
+                Ok(line) => line,
+                Err(LineError::TooLong) => return Err(ParseError::HeaderFieldsTooLarge),
+                Err(LineError::InvalidUtf8) => {
+                    return Err(ParseError::BadRequest("Malformed header"))
+                }
+                Err(LineError::Eof) | Err(LineError::Io) => return Err(ParseError::Io),
+            };
+            if line.is_empty() {
+                break;
+            }
+            headers_size += line.len() + 2;
+            if headers_size > MAX_HEADERS_SIZE {
+                return Err(ParseError::HeaderFieldsTooLarge);
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(ParseError::BadRequest("Malformed header"))?;
+            if name.is_empty() || name.trim_end() != name {
+                return Err(ParseError::BadRequest("Malformed header"));
+            }
+            headers.insert(name, value.trim());
+        }
+
+        if headers.get("Transfer-Encoding").is_some() {
+            return Err(ParseError::BadRequest("Transfer-Encoding is not supported"));
+        }
+        // Only digits, as RFC 9112 requires: `parse` would also take a sign,
+        // which a proxy in front may read differently.
+        let content_length = match headers.get("Content-Length") {
+            Some(length)
+                if !length.is_empty() && length.bytes().all(|byte| byte.is_ascii_digit()) =>
+            {
+                length
+                    .parse::<usize>()
+                    .map_err(|_| ParseError::BadRequest("Invalid Content-Length"))?
+            }
+            Some(_) => return Err(ParseError::BadRequest("Invalid Content-Length")),
+            None => 0,
+        };
+        if content_length > MAX_BODY_SIZE {
+            return Err(ParseError::PayloadTooLarge);
+        }
+
+        let request = Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version,
+            headers,
+            body: Vec::new(),
+        };
+        Ok((request, content_length))
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.contains_token("Connection", "close") {
+            false
+        } else if self.headers.contains_token("Connection", "keep-alive") {
+            true
+        } else {
+            // HTTP/1.1 connections are persistent by default, HTTP/1.0 ones are not.
+            self.version == Version::Http11
+        }
+    }
+}
+
+impl ParseError {
+    /// Errors caused by the peer going away get no response at all.
+    pub fn to_response(&self) -> Option<Response> {
+        let error = match self {
+            Self::ConnectionClosed | Self::Io => return None,
+            Self::BadRequest(message) => ApiError::bad_request("malformed_request", *message),
+            Self::PayloadTooLarge => ApiError::new(
+                413,
+                "payload_too_large",
+                format!("Request body exceeds {MAX_BODY_SIZE} bytes"),
+            ),
+            Self::UriTooLong => ApiError::new(
+                414,
+                "uri_too_long",
+                format!("Request line exceeds {MAX_REQUEST_LINE_SIZE} bytes"),
+            ),
+            Self::HeaderFieldsTooLarge => ApiError::new(
+                431,
+                "header_fields_too_large",
+                format!("Request headers exceed {MAX_HEADERS_SIZE} bytes"),
+            ),
+            Self::VersionNotSupported => ApiError::new(
+                505,
+                "http_version_not_supported",
+                "Only HTTP/1.0 and HTTP/1.1 are supported",
+            ),
+        };
+        Some(Response::from(error))
+    }
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b',' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes.clone().take(2).collect::<Vec<u8>>();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        bytes.nth(1);
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reads a single CRLF (or bare LF) terminated line of at most `limit` bytes,
+/// without the line terminator.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> Result<String, LineError> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(limit as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .map_err(|_| LineError::Io)?;
+    if read == 0 {
+        return Err(LineError::Eof);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() > limit {
+            LineError::TooLong
+        } else {
+            LineError::Eof
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.len() > limit {
+        return Err(LineError::TooLong);
+    }
+    String::from_utf8(line).map_err(|_| LineError::InvalidUtf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<Request, ParseError> {
+        let mut reader = raw;
+        Request::read_from(&mut reader)
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            parse(b"POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\ntest")
+                .unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/users");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("localhost"));
+        assert_eq!(request.body, b"test");
+    }
+
+    #[test]
+    fn test_path_and_query() {
+        let request =
+            parse(b"GET /users?limit=10&name=J%C3%B3zef+K&flag HTTP/1.1\r\n\r\n").unwrap();
+        let query = request.query();
+
+        assert_eq!(request.path(), "/users");
+        assert_eq!(query.get("limit"), Some("10"));
+        assert_eq!(query.get("name"), Some("Józef K"));
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get("offset"), None);
+        assert_eq!(Query::parse("a=%zz%4").get("a"), Some("%zz%4"));
+    }
+
+    #[test]
+    fn test_query_round_trip() {
+        let mut query = Query::parse("name=J%C3%B3zef+K&limit=10");
+        query.set("offset", "20");
+        query.remove("limit");
+
+        assert_eq!(query.to_string(), "name=J%C3%B3zef%20K&offset=20");
+        assert_eq!(Query::parse(&query.to_string()), query);
+    }
+
+    #[test]
+    fn test_headers_are_case_insensitive() {
+        let request =
+            parse(b"GET /users HTTP/1.1\r\ncontent-length: 0\r\nX-Test: a\r\nx-test: b\r\n\r\n")
+                .unwrap();
+
+        assert_eq!(request.headers.get("Content-Length"), Some("0"));
+        assert_eq!(request.headers.get("X-TEST"), Some("a, b"));
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let mut reader: &[u8] = b"GET /users HTTP/1.1\r\n\r\n\r\nGET /users/1 HTTP/1.1\r\n\r\n";
+
+        assert_eq!(Request::read_from(&mut reader).unwrap().target, "/users");
+        assert_eq!(Request::read_from(&mut reader).unwrap().target, "/users/1");
+        assert_eq!(
+            Request::read_from(&mut reader),
+            Err(ParseError::ConnectionClosed)
+        );
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let request = parse(b"GET /users HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.keep_alive());
+
+        let request = parse(b"GET /users HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(!request.keep_alive());
+
+        let request = parse(b"GET /users HTTP/1.0\r\n\r\n").unwrap();
+        assert!(!request.keep_alive());
+
+        let request = parse(b"GET /users HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap();
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn test_malformed_request_line() {
+        assert!(matches!(
+            parse(b"GET\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+        assert!(matches!(
+            parse(b"GET users HTTP/1.1\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+        assert!(matches!(
+            parse(b"GET /users HTTP/1.1 extra\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+        assert!(matches!(
+            parse(b"GET /users FTP\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_non_utf8_request_line() {
+        assert!(matches!(
+            parse(b"GET /\xff HTTP/1.1\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        assert!(matches!(
+            parse(b"GET /users HTTP/1.1\r\nHost localhost\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+        assert!(matches!(
+            parse(b"GET /users HTTP/1.1\r\nContent-Length: abc\r\n\r\n"),
+            Err(ParseError::BadRequest(_))
+        ));
+        for length in ["+5", "-0", ""] {
+            let request = format!("POST /users HTTP/1.1\r\nContent-Length: {length}\r\n\r\nhello");
+            assert_eq!(
+                parse(request.as_bytes()),
+                Err(ParseError::BadRequest("Invalid Content-Length")),
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        assert_eq!(
+            parse(b"GET /users HTTP/2.0\r\n\r\n"),
+            Err(ParseError::VersionNotSupported)
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let long_target = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_REQUEST_LINE_SIZE)
+        );
+        assert_eq!(parse(long_target.as_bytes()), Err(ParseError::UriTooLong));
+
+        let long_header = format!(
+            "GET /users HTTP/1.1\r\nX-Test: {}\r\n\r\n",
+            "a".repeat(MAX_HEADERS_SIZE)
+        );
+        assert_eq!(
+            parse(long_header.as_bytes()),
+            Err(ParseError::HeaderFieldsTooLarge)
+        );
+
+        let header = |size: usize| {
+            let field = format!("X-Test: {}", "a".repeat(size - "X-Test: ".len()));
+            format!("GET /users HTTP/1.1\r\n{field}\r\n\r\n")
+        };
+        assert!(parse(header(MAX_HEADERS_SIZE - 2).as_bytes()).is_ok());
+        for size in [MAX_HEADERS_SIZE - 1, MAX_HEADERS_SIZE] {
+            assert_eq!(
+                parse(header(size).as_bytes()),
+                Err(ParseError::HeaderFieldsTooLarge),
+                "{size}"
+            );
+        }
+
+        let large_body = format!(
+            "POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(
+            parse(large_body.as_bytes()),
+            Err(ParseError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_client_disconnects_mid_request() {
+        assert_eq!(parse(b""), Err(ParseError::ConnectionClosed));
+        assert_eq!(
+            parse(b"GET /users HTTP/1.1\r\nHost: local"),
+            Err(ParseError::Io)
+        );
+        assert_eq!(
+            parse(b"POST /users HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(ParseError::Io)
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, Write};
+
+use crate::error::ApiError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(code: u16, body: String) -> Self {
+        Self {
+            code,
+            headers: Vec::new(),
+            body,
+        }
+    }
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    /// 204 and 304 responses never carry a body, whatever `body` holds.
+    pub fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.code, reason_phrase(self.code));
+        for (name, value) in &self.headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let bodiless = matches!(self.code, 204 | 304);
+        if !bodiless {
+            response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        response.push_str(&format!("Connection: {connection}\r\n\r\n"));
+        if !bodiless {
+            response.push_str(&self.body);
+        }
+        writer.write_all(response.as_bytes())?;
+        writer.flush()
+    }
+}
+
+impl From<ApiError> for Response {
+    fn from(error: ApiError) -> Self {
+        Self::new(error.status, error.to_problem_json())
+            .with_header("Content-Type", "application/problem+json")
+    }
+}
+
+pub(crate) fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        428 => "Precondition Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_response() {
+        let mut output = Vec::new();
+        Response::new(200, "[]".to_string())
+            .with_header("Content-Type", "application/json")
+            .write_to(&mut output, true)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: 2\r\nConnection: keep-alive\r\n\r\n[]"
+        );
+    }
+
+    #[test]
+    fn test_bodiless_responses() {
+        for code in [204, 304] {
+            let mut output = Vec::new();
+            Response::new(code, "ignored".to_string())
+                .write_to(&mut output, false)
+                .unwrap();
+
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.ends_with("Connection: close\r\n\r\n"), "{output}");
+            assert!(!output.contains("Content-Length"), "{output}");
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{error::ApiError, request::Request, response::Response};
+
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params {
+    values: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    method: String,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Params {
+    /// Parses the named path parameter, a value that does not parse is a 400.
+    pub fn get<T: FromStr>(&self, name: &str) -> Result<T, ApiError> {
+        let value = self.values.get(name).map_or("", String::as_str);
+        value.parse().map_err(|_| {
+            ApiError::bad_request("invalid_path_parameter", "Invalid path parameter").with_field(
+                name,
+                "invalid_format",
+                format!("'{value}' is not a valid {name}"),
+            )
+        })
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Registers `handler` for `method` requests whose path matches `pattern`.
+    /// Segments written as `{name}` match any value and are exposed through
+    /// [`Params`].
+    pub fn route<F>(&mut self, method: &str, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        let pattern = segments(pattern)
+            .map(|segment| {
+                match segment
+                    .strip_prefix('{')
+                    .and_then(|name| name.strip_suffix('}'))
+                {
+                    Some(name) => Segment::Param(name.to_string()),
+                    None => Segment::Literal(segment.to_string()),
+                }
+            })
+            .collect();
+        self.routes.push(Route {
+            method: method.to_string(),
+            pattern,
+            handler: Box::new(handler),
+        });
+        self
+    }
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        self.route("POST", pattern, handler)
+    }
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        self.route("PUT", pattern, handler)
+    }
+    pub fn patch<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        self.route("PATCH", pattern, handler)
+    }
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &Params) -> Result<Response, ApiError> + Send + Sync + 'static,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Dispatches the request to the first matching route. A path that is
+    /// known under other methods only gets a 405 with an `Allow` header.
+    pub fn handle(&self, request: &Request) -> Response {
+        let path: Vec<&str> = segments(request.path()).collect();
+
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.matches(&path) else {
+                continue;
+            };
+            if route.method == request.method {
+                return (route.handler)(request, &params).unwrap_or_else(Response::from);
+            }
+            if !allowed.contains(&route.method.as_str()) {
+                allowed.push(route.method.as_str());
+            }
+        }
+
+        if allowed.is_empty() {
+            Response::from(ApiError::new(
+                404,
+                "not_found",
+                format!("No resource at {}", request.path()),
+            ))
+        } else {
+            allowed.sort();
+            let allowed = allowed.join(", ");
+            let error = ApiError::new(
+                405,
+                "method_not_allowed",
+                format!(
+                    "{} is not allowed here, use one of: {allowed}",
+                    request.method
+                ),
+            );
+            Response::from(error).with_header("Allow", &allowed)
+        }
+    }
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        if self.pattern.len() != path.len() {
+            return None;
+        }
+        let mut params = Params::default();
+        for (segment, value) in self.pattern.iter().zip(path) {
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.values.insert(name.clone(), value.to_string());
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Splits a path into its segments, ignoring the leading and a trailing slash.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let path = path.strip_suffix('/').unwrap_or(path);
+    path.split('/').filter(move |_| !path.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Headers, Version};
+
+    fn create_request(method: &str, target: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn create_router() -> Router {
+        let mut router = Router::new();
+        router
+            .get("/users", |_, _| Ok(Response::new(200, "list".to_string())))
+            .post("/users", |_, _| Ok(Response::new(201, "add".to_string())))
+            .get("/users/{id}", |_, params| {
+                let id: u32 = params.get("id")?;
+                Ok(Response::new(200, format!("user {id}")))
+            })
+            .delete("/users/{id}", |_, _| {
+                Ok(Response::new(204, "delete".to_string()))
+            });
+        router
+    }
+
+    #[test]
+    fn test_routes_by_method_and_path() {
+        let router = create_router();
+
+        assert_eq!(router.handle(&create_request("GET", "/users")).body, "list");
+        assert_eq!(router.handle(&create_request("POST", "/users")).body, "add");
+        assert_eq!(
+            router.handle(&create_request("DELETE", "/users/1")).body,
+            "delete"
+        );
+    }
+
+    #[test]
+    fn test_extracts_path_params() {
+        let router = create_router();
+        let response = router.handle(&create_request("GET", "/users/42"));
+
+        assert_eq!(response.code, 200);
+        assert_eq!(response.body, "user 42");
+    }
+
+    #[test]
+    fn test_invalid_path_param() {
+        let router = create_router();
+        let response = router.handle(&create_request("GET", "/users/test/"));
+
+        assert_eq!(response.code, 400);
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let router = create_router();
+        let response = router.handle(&create_request("PATCH", "/users"));
+
+        assert_eq!(response.code, 405);
+        assert_eq!(response.header("Allow"), Some("GET, POST"));
+    }
+
+    #[test]
+    fn test_not_found() {
+        let router = create_router();
+
+        assert_eq!(router.handle(&create_request("GET", "/groups")).code, 404);
+        assert_eq!(router.handle(&create_request("GET", "/")).code, 404);
+        assert_eq!(
+            router.handle(&create_request("GET", "/users/1/posts")).code,
+            404
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db_object::RecordMeta,
+    wal::{invalid_data, sync_parent},
+    User,
+};
+
+/// The payload length as a little endian `u64` and its CRC32 as a `u32`,
+/// without the log's record size limit: a snapshot holds every user.
+const HEADER_SIZE: usize = 12;
+
+/// The complete state of a store at one point in time, written as a single
+/// checksummed record.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Grows with every snapshot, the log after it starts with a matching
+    /// [`crate::wal::LogEntry::Checkpoint`].
+    pub generation: u64,
+    pub next_id: u32,
+    /// The counter the users' versions come from.
+    pub version: u64,
+    pub users: Vec<(User, RecordMeta)>,
+}
+
+impl Snapshot {
+    /// Reads the snapshot at `path`, `None` when there is none yet.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let Some((header, payload)) = bytes.split_at_checked(HEADER_SIZE) else {
+            return Err(invalid_data("snapshot is incomplete"));
+        };
+        let len = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if len != payload.len() as u64 || crc32fast::hash(payload) != crc {
+            return Err(invalid_data("snapshot is incomplete"));
+        }
+        let snapshot = serde_json::from_slice(payload)
+            .map_err(|_| invalid_data("snapshot with a valid checksum could not be decoded"))?;
+        Ok(Some(snapshot))
+    }
+
+    /// Replaces the snapshot at `path`. The new one is written next to it and
+    /// renamed over it once it is on disk, so a crash leaves either the old
+    /// or the new snapshot, never a mix.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let temporary = temporary_path(path);
+        let mut file = File::create(&temporary)?;
+        file.write_all(&self.encode()?)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&temporary, path)?;
+        // Makes the rename itself durable.
+        sync_parent(path)
+    }
+
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let payload = serde_json::to_vec(self)?;
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wal::tests::temp_path, UserGroup};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn create_snapshot(generation: u64) -> Snapshot {
+        Snapshot {
+            generation,
+            next_id: 8,
+            version: 5,
+            users: vec![(
+                User {
+                    id: 3,
+                    name: "Hlib".to_string(),
+                    lastname: "Shutov".to_string(),
+                    birth_year: 2000,
+                    group: UserGroup::Admin,
+                },
+                RecordMeta {
+                    version: 4,
+                    modified: UNIX_EPOCH + Duration::from_secs(784_111_777),
+                },
+            )],
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path = temp_path("snapshot");
+        assert_eq!(Snapshot::read(&path).unwrap(), None);
+
+        create_snapshot(1).write(&path).unwrap();
+        create_snapshot(2).write(&path).unwrap();
+
+        assert_eq!(Snapshot::read(&path).unwrap(), Some(create_snapshot(2)));
+        assert!(!temporary_path(&path).exists());
+    }
+
+    #[test]
+    fn test_snapshot_larger_than_a_log_record() {
+        let path = temp_path("snapshot_large");
+        let mut snapshot = create_snapshot(1);
+        let (user, meta) = snapshot.users[0].clone();
+        snapshot.users = (0..17)
+            .map(|id| {
+                let user = User {
+                    id,
+                    name: "a".repeat(1024 * 1024),
+                    ..user.clone()
+                };
+                (user, meta)
+            })
+            .collect();
+        snapshot.write(&path).unwrap();
+
+        assert!(fs::metadata(&path).unwrap().len() > 16 * 1024 * 1024);
+        assert_eq!(Snapshot::read(&path).unwrap(), Some(snapshot));
+    }
+
+    #[test]
+    fn test_damaged_snapshot_is_an_error() {
+        let path = temp_path("snapshot_damaged");
+        create_snapshot(1).write(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+
+        let error = Snapshot::read(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,822 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError, TryLockError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{
+    params, params_from_iter, types::Value, Connection, OpenFlags, OptionalExtension, Row,
+    Transaction,
+};
+
+use crate::{
+    db_object::{FieldValue, RecordMeta, SortKey, SortPosition, UserEnum, UserField, UserFilter},
+    error::ApiError,
+    pagination::{Page, PageRequest, PageStart},
+    store::UserStore,
+    transaction::Operation,
+    User, UserGroup,
+};
+
+/// Schema changes in the order they were made. `PRAGMA user_version` holds
+/// how many of them a database file has seen, so new ones run exactly once.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        lastname TEXT NOT NULL,
+        birth_year INTEGER NOT NULL,
+        user_group TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        modified INTEGER NOT NULL
+    );
+    CREATE INDEX users_by_group ON users (user_group);
+    CREATE INDEX users_by_birth_year ON users (birth_year);
+    CREATE TABLE store (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        next_id INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        modified INTEGER NOT NULL
+    );
+    INSERT INTO store VALUES (0, 0, 0, 0);
+"];
+
+const USER_COLUMNS: &str = "id, name, lastname, birth_year, user_group";
+
+/// Read-only connections opened next to the writing one, as many as the
+/// server has workers by default.
+const READERS: usize = 4;
+
+/// A store kept in a SQLite database, for deployments that want to inspect
+/// or back up the users with SQL tools.
+///
+/// Versions, ids and timestamps follow the same rules as in [`DataBase`]:
+/// one version counter for all users and ids that are never reused.
+/// Filters, ordering and pages are evaluated by SQLite.
+///
+/// [`DataBase`]: crate::db_object::DataBase
+#[derive(Debug)]
+pub struct SqliteStore {
+    /// Writes go through this connection, and reads too when there are no
+    /// `readers`.
+    connection: Mutex<Connection>,
+    /// A connection must not be used by two threads at once, so concurrent
+    /// reads are spread over these. The database is in WAL mode, so they
+    /// never wait for the file lock of the writing connection.
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path` and brings its schema up to
+    /// date.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let path = path.as_ref();
+        let connection = Connection::open(path)?;
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        let mut store = Self::migrate(connection)?;
+        store.readers = (0..READERS)
+            .map(|_| {
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+                Connection::open_with_flags(path, flags).map(Mutex::new)
+            })
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(store)
+    }
+
+    /// Every connection to `:memory:` has a database of its own, so reads
+    /// here take turns on the single connection.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::migrate(Connection::open_in_memory()?)
+    }
+
+    fn migrate(mut connection: Connection) -> rusqlite::Result<Self> {
+        let transaction = connection.transaction()?;
+        let applied: usize = transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for migration in MIGRATIONS.iter().skip(applied) {
+            transaction.execute_batch(migration)?;
+        }
+        transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        transaction.commit()?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            readers: Vec::new(),
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    /// A connection for reading: the first idle reader, starting from a
+    /// different one on every call, or the next one in turn when all are
+    /// busy. Reads leave nothing half done when they panic, so a poisoned
+    /// lock is still safe to use.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        if self.readers.is_empty() {
+            return self
+                .connection
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.readers.len() {
+            match self.readers[(start + offset) % self.readers.len()].try_lock() {
+                Ok(reader) => return reader,
+                Err(TryLockError::Poisoned(error)) => return error.into_inner(),
+                Err(TryLockError::WouldBlock) => {}
+            }
+        }
+        self.readers[start % self.readers.len()]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn connection_mut(&mut self) -> &mut Connection {
+        self.connection
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Runs `write` in a transaction that is committed only when it
+    /// succeeds.
+    fn write<T>(
+        &mut self,
+        write: impl FnOnce(&Transaction) -> Result<T, ApiError>,
+    ) -> Result<T, ApiError> {
+        let transaction = self.connection_mut().transaction().map_err(storage_error)?;
+        let result = write(&transaction)?;
+        transaction.commit().map_err(storage_error)?;
+        Ok(result)
+    }
+}
+
+fn count(connection: &Connection, condition: &Condition) -> rusqlite::Result<usize> {
+    connection.query_row(
+        &format!("SELECT COUNT(*) FROM users WHERE {}", condition.sql),
+        params_from_iter(&condition.params),
+        |row| row.get(0),
+    )
+}
+
+fn select(
+    connection: &Connection,
+    condition: &Condition,
+    sort: &[SortKey],
+    reversed: bool,
+    limit: Option<usize>,
+    offset: usize,
+) -> rusqlite::Result<Vec<User>> {
+    // A negative limit means no limit.
+    let limit = limit.map_or(-1, |limit| limit as i64);
+    let mut statement = connection.prepare_cached(&format!(
+        "SELECT {USER_COLUMNS} FROM users WHERE {} {} LIMIT {limit} OFFSET {offset}",
+        condition.sql,
+        order_by(sort, reversed),
+    ))?;
+    let users = statement.query_map(params_from_iter(&condition.params), user_from_row)?;
+    users.collect()
+}
+
+/// Slices the page with SQL. Cursors become a comparison against the
+/// sort position, so, as with [`PageRequest::paginate`], they stay valid
+/// when users are removed.
+fn select_page(
+    connection: &Connection,
+    filter: &UserFilter,
+    sort: &[SortKey],
+    request: &PageRequest,
+) -> rusqlite::Result<Page<Vec<User>>> {
+    let filtered = Condition::filter(filter);
+    let total = count(connection, &filtered)?;
+    let limit = request.limit;
+    let page = Some(limit);
+
+    let (start, items) = match &request.start {
+        PageStart::First => (0, select(connection, &filtered, sort, false, page, 0)?),
+        PageStart::Offset(offset) => {
+            let start = (*offset).min(total);
+            (
+                start,
+                select(connection, &filtered, sort, false, page, start)?,
+            )
+        }
+        PageStart::After(position) => {
+            let after = Condition::after(sort, position, false);
+            (
+                count(connection, &filtered.and(after.clone().not()))?,
+                select(connection, &filtered.and(after), sort, false, page, 0)?,
+            )
+        }
+        PageStart::UpTo(position) => {
+            let not_after = filtered.and(Condition::after(sort, position, false).not());
+            let end = count(connection, &not_after)?;
+            let mut items = select(connection, &not_after, sort, true, page, 0)?;
+            items.reverse();
+            (end - items.len(), items)
+        }
+    };
+    let end = start + items.len();
+
+    let cursor_based = !matches!(request.start, PageStart::Offset(_));
+    let next = (end < total).then(|| PageRequest {
+        limit,
+        start: match items.last() {
+            _ if !cursor_based => PageStart::Offset(end),
+            Some(last) => PageStart::After(SortPosition::of(last, sort)),
+            None => PageStart::First,
+        },
+    });
+    let prev = if start == 0 {
+        None
+    } else if cursor_based {
+        // The user right before the page, found like the page itself.
+        let before = match (&request.start, items.first()) {
+            (_, Some(first)) => Condition::after(sort, &SortPosition::of(first, sort), true),
+            (PageStart::After(position), None) => Condition::after(sort, position, false).not(),
+            _ => unreachable!("pages up to a position are empty only at the start"),
+        };
+        let previous = select(connection, &filtered.and(before), sort, true, Some(1), 0)?;
+        previous.first().map(|user| PageRequest {
+            limit,
+            start: PageStart::UpTo(SortPosition::of(user, sort)),
+        })
+    } else {
+        Some(PageRequest {
+            limit,
+            start: PageStart::Offset(start.saturating_sub(limit)),
+        })
+    };
+
+    Ok(Page {
+        items,
+        total,
+        next,
+        prev,
+    })
+}
+
+/// A SQL expression with its positional parameters.
+#[derive(Clone, Debug)]
+struct Condition {
+    sql: String,
+    params: Vec<Value>,
+}
+
+impl Condition {
+    fn new(sql: impl Into<String>, params: Vec<Value>) -> Self {
+        Self {
+            sql: sql.into(),
+            params,
+        }
+    }
+
+    fn filter(filter: &UserFilter) -> Self {
+        let mut condition = Self::new("1", Vec::new());
+        if let Some(group) = &filter.group {
+            condition = condition.and(Self::new(
+                "user_group = ?",
+                vec![Value::Text(group_name(group).to_string())],
+            ));
+        }
+        if let Some(year) = filter.birth_year {
+            condition = condition.and(Self::new("birth_year = ?", vec![year.into()]));
+        }
+        if let Some(year) = filter.birth_year_gte {
+            condition = condition.and(Self::new("birth_year >= ?", vec![year.into()]));
+        }
+        if let Some(year) = filter.birth_year_lte {
+            condition = condition.and(Self::new("birth_year <= ?", vec![year.into()]));
+        }
+        for (column, prefix) in [
+            ("name", &filter.name_prefix),
+            ("lastname", &filter.lastname_prefix),
+        ] {
+            if let Some(prefix) = prefix {
+                // Unlike LIKE this is case sensitive and has no wildcards.
+                condition = condition.and(Self::new(
+                    format!("substr({column}, 1, length(?)) = ?"),
+                    vec![prefix.clone().into(), prefix.clone().into()],
+                ));
+            }
+        }
+        condition
+    }
+
+    /// Users strictly after `position` in `sort` order, or strictly before
+    /// it when `reversed`.
+    fn after(sort: &[SortKey], position: &SortPosition, reversed: bool) -> Self {
+        let columns = sort
+            .iter()
+            .zip(&position.values)
+            .map(|(key, value)| (column(key.field), key.descending, field_value(value)))
+            .chain([("id", false, Value::from(position.id))]);
+
+        // (a > x) OR (a = x AND b > y) OR ...
+        let mut equal = Self::new("1", Vec::new());
+        let mut any = Self::new("0", Vec::new());
+        for (column, descending, value) in columns {
+            let operator = if descending != reversed { "<" } else { ">" };
+            let beyond = Self::new(format!("{column} {operator} ?"), vec![value.clone()]);
+            any = any.or(equal.and(beyond));
+            equal = equal.and(Self::new(format!("{column} = ?"), vec![value]));
+        }
+        any
+    }
+
+    fn and(&self, other: Self) -> Self {
+        self.join("AND", other)
+    }
+
+    fn or(&self, other: Self) -> Self {
+        self.join("OR", other)
+    }
+
+    fn not(self) -> Self {
+        Self::new(format!("NOT ({})", self.sql), self.params)
+    }
+
+    fn join(&self, operator: &str, other: Self) -> Self {
+        let mut params = self.params.clone();
+        params.extend(other.params);
+        Self::new(format!("({}) {operator} ({})", self.sql, other.sql), params)
+    }
+}
+
+fn order_by(sort: &[SortKey], reversed: bool) -> String {
+    let keys: Vec<String> = sort
+        .iter()
+        .map(|key| (column(key.field), key.descending))
+        .chain([("id", false)])
+        .map(|(column, descending)| {
+            let direction = if descending != reversed {
+                "DESC"
+            } else {
+                "ASC"
+            };
+            format!("{column} {direction}")
+        })
+        .collect();
+    format!("ORDER BY {}", keys.join(", "))
+}
+
+fn column(field: UserField) -> &'static str {
+    match field {
+        UserField::Id => "id",
+        UserField::Name => "name",
+        UserField::Lastname => "lastname",
+        UserField::BirthYear => "birth_year",
+        UserField::Group => "user_group",
+    }
+}
+
+/// Numbers sort before text in SQLite just like in [`FieldValue`], so
+/// cursors compare the same way as in memory.
+fn field_value(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Number(number) => Value::Integer(i64::try_from(*number).unwrap_or(i64::MAX)),
+        FieldValue::Text(text) => Value::Text(text.clone()),
+    }
+}
+
+/// Groups are stored under the names they sort by, see [`UserField::value`].
+fn group_name(group: &UserGroup) -> &'static str {
+    match group {
+        UserGroup::User => "User",
+        UserGroup::Premium => "Premium",
+        UserGroup::Admin => "Admin",
+    }
+}
+
+fn parse_group(name: &str) -> Option<UserGroup> {
+    [UserGroup::User, UserGroup::Premium, UserGroup::Admin]
+        .into_iter()
+        .find(|group| group_name(group) == name)
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let group: String = row.get(4)?;
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        lastname: row.get(2)?,
+        birth_year: row.get(3)?,
+        group: parse_group(&group).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                4,
+                rusqlite::types::Type::Text,
+                format!("unknown group {group}").into(),
+            )
+        })?,
+    })
+}
+
+fn to_nanos(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+fn from_nanos(nanos: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64)
+}
+
+/// Bumps the version counter and the modification time, returning both.
+fn touch(transaction: &Transaction) -> rusqlite::Result<(i64, i64)> {
+    let modified = to_nanos(SystemTime::now());
+    let version = transaction.query_row(
+        "UPDATE store SET version = version + 1, modified = ?1 RETURNING version",
+        [modified],
+        |row| row.get(0),
+    )?;
+    Ok((version, modified))
+}
+
+fn storage_error(error: rusqlite::Error) -> ApiError {
+    eprintln!("SQLite request failed: {error}");
+    ApiError::storage()
+}
+
+fn insert_user(
+    transaction: &Transaction,
+    user: User,
+    new_id: Option<u32>,
+) -> Result<u32, ApiError> {
+    let next_id: u32 = transaction
+        .query_row("SELECT next_id FROM store", [], |row| row.get(0))
+        .map_err(storage_error)?;
+    let id = match new_id {
+        Some(id) => {
+            let taken = transaction
+                .query_row("SELECT 1 FROM users WHERE id = ?1", [id], |_| Ok(()))
+                .optional()
+                .map_err(storage_error)?;
+            if taken.is_some() {
+                return Err(ApiError::user_exists(id));
+            }
+            id
+        }
+        None => next_id,
+    };
+    let next_id = next_id
+        .max(id.checked_add(1).ok_or_else(|| {
+            ApiError::new(507, "ids_exhausted", "No more user ids are available")
+        })?);
+
+    let (version, modified) = touch(transaction).map_err(storage_error)?;
+    transaction
+        .execute(
+            "INSERT INTO users VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                id,
+                user.name,
+                user.lastname,
+                user.birth_year,
+                group_name(&user.group),
+                version,
+                modified
+            ],
+        )
+        .and_then(|_| transaction.execute("UPDATE store SET next_id = ?1", [next_id]))
+        .map_err(storage_error)?;
+    Ok(id)
+}
+
+fn delete_user(transaction: &Transaction, id: u32) -> Result<(), ApiError> {
+    let removed = transaction
+        .execute("DELETE FROM users WHERE id = ?1", [id])
+        .map_err(storage_error)?;
+    if removed == 0 {
+        return Err(ApiError::user_not_found(id));
+    }
+    transaction
+        .execute(
+            "UPDATE store SET modified = ?1",
+            [to_nanos(SystemTime::now())],
+        )
+        .map_err(storage_error)?;
+    Ok(())
+}
+
+fn update_user(transaction: &Transaction, id: u32, data: Vec<UserEnum>) -> Result<(), ApiError> {
+    let mut user = transaction
+        .query_row(
+            &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"),
+            [id],
+            user_from_row,
+        )
+        .optional()
+        .map_err(storage_error)?
+        .ok_or_else(|| ApiError::user_not_found(id))?;
+
+    for change in data {
+        match change {
+            UserEnum::Name(name) => user.name = name,
+            UserEnum::Lastname(lastname) => user.lastname = lastname,
+            UserEnum::BirthYear(birth_year) => user.birth_year = birth_year,
+            UserEnum::Group(group) => user.group = group,
+        }
+    }
+    let (version, modified) = touch(transaction).map_err(storage_error)?;
+    transaction
+        .execute(
+            "UPDATE users SET name = ?2, lastname = ?3, birth_year = ?4, user_group = ?5,
+                version = ?6, modified = ?7 WHERE id = ?1",
+            params![
+                id,
+                user.name,
+                user.lastname,
+                user.birth_year,
+                group_name(&user.group),
+                version,
+                modified
+            ],
+        )
+        .map_err(storage_error)?;
+    Ok(())
+}
+
+impl UserStore for SqliteStore {
+    fn add_entry(&mut self, user: User, new_id: Option<u32>) -> Result<u32, ApiError> {
+        self.write(|transaction| insert_user(transaction, user, new_id))
+    }
+    fn remove_entry(&mut self, id: u32) -> Result<(), ApiError> {
+        self.write(|transaction| delete_user(transaction, id))
+    }
+    fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<(), ApiError> {
+        self.write(|transaction| update_user(transaction, id, data))
+    }
+    /// Runs all operations in one SQL transaction.
+    fn commit(&mut self, operations: Vec<Operation>) -> Result<Vec<u32>, ApiError> {
+        self.write(|transaction| {
+            operations
+                .into_iter()
+                .enumerate()
+                .map(|(index, operation)| {
+                    match operation {
+                        Operation::Add { user, id } => insert_user(transaction, user, id),
+                        Operation::Change { id, data } => {
+                            update_user(transaction, id, data).map(|_| id)
+                        }
+                        Operation::Remove { id } => delete_user(transaction, id).map(|_| id),
+                    }
+                    .map_err(|error| error.in_operation(index))
+                })
+                .collect()
+        })
+    }
+    fn get_one(&self, id: u32) -> Result<User, ApiError> {
+        self.connection()
+            .query_row(
+                &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"),
+                [id],
+                user_from_row,
+            )
+            .optional()
+            .map_err(storage_error)?
+            .ok_or_else(|| ApiError::user_not_found(id))
+    }
+    fn get_all(&self) -> Result<Vec<User>, ApiError> {
+        self.query(&UserFilter::default())
+    }
+    fn query(&self, filter: &UserFilter) -> Result<Vec<User>, ApiError> {
+        select(
+            &self.connection(),
+            &Condition::filter(filter),
+            &[],
+            false,
+            None,
+            0,
+        )
+        .map_err(storage_error)
+    }
+    fn page(
+        &self,
+        filter: &UserFilter,
+        sort: &[SortKey],
+        request: &PageRequest,
+    ) -> Result<Page<Vec<User>>, ApiError> {
+        select_page(&self.connection(), filter, sort, request).map_err(storage_error)
+    }
+    fn get_meta(&self, id: u32) -> Result<Option<RecordMeta>, ApiError> {
+        self.connection()
+            .query_row(
+                "SELECT version, modified FROM users WHERE id = ?1",
+                [id],
+                |row| {
+                    Ok(RecordMeta {
+                        version: row.get(0)?,
+                        modified: from_nanos(row.get(1)?),
+                    })
+                },
+            )
+            .optional()
+            .map_err(storage_error)
+    }
+    fn last_modified(&self) -> Result<SystemTime, ApiError> {
+        self.connection()
+            .query_row("SELECT modified FROM store", [], |row| {
+                Ok(from_nanos(row.get(0)?))
+            })
+            .map_err(storage_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db_object::DataBase, transaction::Transaction, wal::tests::temp_path};
+
+    fn create_users() -> Vec<User> {
+        let names = ["Jan", "Anna", "Jakub", "Ola", "Anna", "Piotr", "Jan"];
+        let groups = [UserGroup::User, UserGroup::Premium, UserGroup::Admin];
+        names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| User {
+                id: index as u32 * 2,
+                name: name.to_string(),
+                lastname: format!("Nowak{}", index % 3),
+                birth_year: 1990 + (index as u16 % 4),
+                group: groups[index % 3].clone(),
+            })
+            .collect()
+    }
+
+    fn create_store() -> SqliteStore {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        for user in create_users() {
+            let id = user.id;
+            store.add_entry(user, Some(id)).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_writes() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let user = create_users().remove(0);
+
+        assert_eq!(store.add_entry(user.clone(), None), Ok(0));
+        assert_eq!(store.add_entry(user.clone(), Some(5)), Ok(5));
+        assert_eq!(
+            store.add_entry(user.clone(), Some(5)),
+            Err(ApiError::user_exists(5))
+        );
+        assert_eq!(store.add_entry(user.clone(), None), Ok(6));
+
+        let version = store.get_meta(6).unwrap().unwrap().version;
+        store
+            .change_user(6, vec![UserEnum::Group(UserGroup::Admin)])
+            .unwrap();
+        assert_eq!(store.get_one(6).unwrap().group, UserGroup::Admin);
+        assert!(store.get_meta(6).unwrap().unwrap().version > version);
+
+        store.remove_entry(6).unwrap();
+        assert_eq!(store.get_one(6), Err(ApiError::user_not_found(6)));
+        assert_eq!(store.get_meta(6), Ok(None));
+        assert_eq!(store.remove_entry(6), Err(ApiError::user_not_found(6)));
+        assert_eq!(
+            store.change_user(6, Vec::new()),
+            Err(ApiError::user_not_found(6))
+        );
+        assert_eq!(store.add_entry(user, None), Ok(7));
+    }
+
+    #[test]
+    fn test_commit_is_all_or_nothing() {
+        let mut store = create_store();
+        let before = store.get_all().unwrap();
+        let last_modified = store.last_modified().unwrap();
+        let user = create_users().remove(0);
+
+        let mut transaction = Transaction::begin();
+        transaction
+            .add(user.clone(), None)
+            .change(0, vec![UserEnum::Name("Ewa".to_string())])
+            .remove(0)
+            .remove(0);
+        let error = transaction.commit(&mut store).unwrap_err();
+        assert_eq!(error.fields[0].field, "operations[3]");
+        assert_eq!(store.get_all().unwrap(), before);
+        assert_eq!(store.last_modified().unwrap(), last_modified);
+
+        let mut transaction = Transaction::begin();
+        transaction.add(user, None).remove(0);
+        assert_eq!(transaction.commit(&mut store), Ok(vec![13, 0]));
+        assert_eq!(store.get_one(13).unwrap().name, "Jan");
+        assert_eq!(store.get_one(0), Err(ApiError::user_not_found(0)));
+    }
+
+    #[test]
+    fn test_reopen_keeps_users() {
+        let path = temp_path("sqlite");
+        let mut store = SqliteStore::open(&path).unwrap();
+        store.add_entry(create_users().remove(0), None).unwrap();
+        store.remove_entry(0).unwrap();
+        store.add_entry(create_users().remove(1), None).unwrap();
+        let before = store.get_all().unwrap();
+        drop(store);
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.get_all().unwrap(), before);
+        assert_eq!(store.add_entry(create_users().remove(2), None), Ok(2));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_reads_use_their_own_connections() {
+        let path = temp_path("sqlite_readers");
+        let mut store = SqliteStore::open(&path).unwrap();
+        store.add_entry(create_users().remove(0), None).unwrap();
+
+        // Holding one reader does not keep another read waiting.
+        let reader = store.connection();
+        assert_eq!(store.get_all().unwrap().len(), 1);
+        assert!(reader
+            .execute("DELETE FROM users", [])
+            .is_err_and(|error| error.to_string().contains("readonly")));
+        drop(reader);
+
+        store.remove_entry(0).unwrap();
+        assert_eq!(store.get_all().unwrap(), Vec::new());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_query() {
+        let store = create_store();
+        let database = DataBase::from_users(create_users());
+        let filters = [
+            UserFilter::default(),
+            UserFilter {
+                group: Some(UserGroup::Premium),
+                ..Default::default()
+            },
+            UserFilter {
+                birth_year_gte: Some(1991),
+                birth_year_lte: Some(1992),
+                name_prefix: Some("Ja".to_string()),
+                ..Default::default()
+            },
+            UserFilter {
+                birth_year: Some(1990),
+                lastname_prefix: Some("nowak".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        for filter in filters {
+            assert_eq!(store.query(&filter), UserStore::query(&database, &filter));
+        }
+    }
+
+    /// Walks every listing forwards and backwards and expects the same pages
+    /// and links as paginating in memory.
+    #[test]
+    fn test_pages_match_in_memory_pagination() {
+        let store = create_store();
+        let database = DataBase::from_users(create_users());
+        let sorts = [
+            vec![],
+            vec![SortKey {
+                field: UserField::Name,
+                descending: false,
+            }],
+            vec![
+                SortKey {
+                    field: UserField::Group,
+                    descending: true,
+                },
+                SortKey {
+                    field: UserField::BirthYear,
+                    descending: false,
+                },
+            ],
+        ];
+        let filter = UserFilter {
+            birth_year_lte: Some(1992),
+            ..Default::default()
+        };
+
+        for sort in &sorts {
+            for start in [PageStart::First, PageStart::Offset(1)] {
+                let mut request = Some(PageRequest { limit: 2, start });
+                let mut last = None;
+                while let Some(current) = request {
+                    let expected = database.page(&filter, sort, &current).unwrap();
+                    assert_eq!(store.page(&filter, sort, &current).unwrap(), expected);
+                    request = expected.next.clone();
+                    last = Some(expected);
+                }
+
+                let mut request = last.unwrap().prev;
+                while let Some(current) = request {
+                    let expected = database.page(&filter, sort, &current).unwrap();
+                    assert_eq!(store.page(&filter, sort, &current).unwrap(), expected);
+                    request = expected.prev;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::SystemTime;
+
+use crate::{
+    db_object::{sort_users, RecordMeta, SortKey, UserEnum, UserFilter},
+    error::ApiError,
+    pagination::{Page, PageRequest},
+    transaction::Operation,
+    User,
+};
+
+/// Storage backend behind the users API. The server only talks to a store
+/// through this trait, so backends can live in other crates.
+///
+/// Errors are reported as [`ApiError`]s, a missing user must be
+/// [`ApiError::user_not_found`] and a taken id [`ApiError::user_exists`].
+/// Reads return owned users and can fail as well, so a store does not have
+/// to keep its users in memory.
+///
+/// The server shares a store behind an `RwLock`: reads take `&self` and run
+/// in parallel, writes take `&mut self` and wait for them.
+pub trait UserStore: Send + Sync {
+    /// Stores `user` under `new_id`, or under a fresh id when `None`, and
+    /// returns the id it was stored under.
+    fn add_entry(&mut self, user: User, new_id: Option<u32>) -> Result<u32, ApiError>;
+    fn remove_entry(&mut self, id: u32) -> Result<(), ApiError>;
+    fn change_user(&mut self, id: u32, data: Vec<UserEnum>) -> Result<(), ApiError>;
+    fn get_one(&self, id: u32) -> Result<User, ApiError>;
+    /// Every user, ordered by id.
+    fn get_all(&self) -> Result<Vec<User>, ApiError>;
+    /// Users matching `filter`, ordered by id.
+    fn query(&self, filter: &UserFilter) -> Result<Vec<User>, ApiError>;
+    /// The page `request` of the users matching `filter`, ordered by `sort`.
+    /// By default the result of [`UserStore::query`] is sorted and sliced,
+    /// stores that can select the page themselves should override it.
+    fn page(
+        &self,
+        filter: &UserFilter,
+        sort: &[SortKey],
+        request: &PageRequest,
+    ) -> Result<Page<Vec<User>>, ApiError> {
+        let users = self.query(filter)?;
+        let mut users: Vec<&User> = users.iter().collect();
+        sort_users(&mut users, sort);
+        Ok(request
+            .paginate(&users, sort)
+            .map(|page| page.into_iter().cloned().collect()))
+    }
+    /// Version information of a user, `None` when it does not exist.
+    fn get_meta(&self, id: u32) -> Result<Option<RecordMeta>, ApiError>;
+    /// When any user was last added, changed or removed.
+    fn last_modified(&self) -> Result<SystemTime, ApiError>;
+
+    /// Applies all `operations` in order, or none of them when one fails,
+    /// and returns the id each one wrote. Errors are reported with
+    /// [`ApiError::in_operation`]. Stores that cannot undo writes do not
+    /// support it.
+    fn commit(&mut self, _operations: Vec<Operation>) -> Result<Vec<u32>, ApiError> {
+        Err(ApiError::new(
+            501,
+            "not_supported",
+            "This store does not support transactions",
+        ))
+    }
+
+    /// Compacts whatever the store keeps on disk. Stores without persistent
+    /// state do not support it.
+    fn compact(&mut self) -> Result<(), ApiError> {
+        Err(ApiError::new(
+            501,
+            "not_supported",
+            "This store does not support compaction",
+        ))
+    }
+}
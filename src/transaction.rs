@@ -0,0 +1,142 @@
+use crate::{db_object::UserEnum, error::ApiError, store::UserStore, User};
+
+/// One write staged in a [`Transaction`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// Adds `user` under `id`, or under a fresh id when `None`.
+    Add {
+        user: User,
+        id: Option<u32>,
+    },
+    Change {
+        id: u32,
+        data: Vec<UserEnum>,
+    },
+    Remove {
+        id: u32,
+    },
+}
+
+/// Writes that reach a store together or not at all.
+///
+/// Operations are only staged until [`Transaction::commit`], which hands
+/// them to [`UserStore::commit`]. Rolling back, or dropping the
+/// transaction, discards them without touching the store.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    pub fn begin() -> Self {
+        Self::default()
+    }
+    pub fn add(&mut self, user: User, id: Option<u32>) -> &mut Self {
+        self.operations.push(Operation::Add { user, id });
+        self
+    }
+    pub fn change(&mut self, id: u32, data: Vec<UserEnum>) -> &mut Self {
+        self.operations.push(Operation::Change { id, data });
+        self
+    }
+    pub fn remove(&mut self, id: u32) -> &mut Self {
+        self.operations.push(Operation::Remove { id });
+        self
+    }
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+    /// Applies the staged operations in order and returns the id of the
+    /// user each of them wrote. When one fails the store is left as it was
+    /// and the error points at the failed operation.
+    pub fn commit<S: UserStore + ?Sized>(self, store: &mut S) -> Result<Vec<u32>, ApiError> {
+        store.commit(self.operations)
+    }
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db_mock::{DataBaseMock, MockCalls},
+        db_object::DataBase,
+        UserGroup,
+    };
+
+    fn create_user(name: &str) -> User {
+        User {
+            id: 0,
+            name: name.to_string(),
+            lastname: "test1".to_string(),
+            birth_year: 2000,
+            group: UserGroup::User,
+        }
+    }
+
+    fn create_database() -> DataBase {
+        let mut database = DataBase::new();
+        database.add_entry(create_user("a"), None).unwrap();
+        database.add_entry(create_user("b"), None).unwrap();
+        database
+    }
+
+    #[test]
+    fn test_commit() {
+        let mut database = create_database();
+        let mut transaction = Transaction::begin();
+        transaction
+            .add(create_user("c"), None)
+            .add(create_user("d"), Some(7))
+            .change(0, vec![UserEnum::Group(UserGroup::Admin)])
+            .remove(1);
+
+        assert_eq!(transaction.commit(&mut database), Ok(vec![2, 7, 0, 1]));
+        let ids: Vec<u32> = database.get_all().iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![0, 2, 7]);
+        assert_eq!(database.get_one(0).unwrap().group, UserGroup::Admin);
+        assert_eq!(database.next_id(), 8);
+    }
+
+    #[test]
+    fn test_failed_commit_changes_nothing() {
+        let mut database = create_database();
+        let before = database.clone();
+        let mut transaction = Transaction::begin();
+        transaction
+            .add(create_user("c"), Some(9))
+            .change(0, vec![UserEnum::Name("x".to_string())])
+            .remove(1)
+            .remove(0)
+            .add(create_user("d"), Some(1))
+            .change(1, vec![UserEnum::BirthYear(1990)])
+            .remove(5);
+
+        let error = transaction.commit(&mut database).unwrap_err();
+        assert_eq!(error.status, 404);
+        assert_eq!(error.fields[0].field, "operations[6]");
+        assert_eq!(database, before);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut database = DataBaseMock::new(Vec::new());
+        let mut transaction = Transaction::begin();
+        transaction
+            .add(create_user("a"), None)
+            .change(0, vec![UserEnum::Name("b".to_string())])
+            .remove(0);
+        transaction.rollback();
+
+        let mut transaction = Transaction::begin();
+        transaction.remove(1);
+        transaction.commit(&mut database).unwrap();
+
+        assert_eq!(
+            database.calls(),
+            vec![MockCalls::Commit {
+                operations: vec![Operation::Remove { id: 1 }]
+            }]
+        );
+    }
+}
@@ -1,133 +1,459 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
+use serde::Deserialize;
+use serde_json::Value;
+
 use crate::{
-    db_object::{DataBase, UserEnum},
-    db_object_enum::DataObjectEnum,
+    conditional::{self, Precondition, Validators},
+    db_object::{SortKey, UserEnum, UserField, UserFilter},
+    error::{ApiError, FieldError},
+    pagination::{Page, PageRequest},
+    request::Query,
+    store::UserStore,
+    transaction::Transaction,
 };
 use crate::{User, UserGroup};
 
-#[derive(Debug, PartialEq)]
-pub enum Errors {
-    ServerError(u16),
-    UserError(u16),
-}
-
-pub struct UserController {
-    database: Arc<Mutex<DataObjectEnum>>,
+pub struct UserController<S: ?Sized> {
+    database: Arc<RwLock<S>>,
 }
 
-impl UserController {
-    pub fn new(database: Arc<Mutex<DataObjectEnum>>) -> Self {
+impl<S: UserStore + ?Sized> UserController<S> {
+    pub fn new(database: Arc<RwLock<S>>) -> Self {
         Self { database }
     }
-    pub fn show_users(&self) -> Result<String, Errors> {
-        let mut users = self.database.lock().unwrap();
-        let json = serde_json::to_string(&*users.get_all()).map_err(|_| Errors::ServerError(500));
-        json
+    /// A handler that panicked while holding the lock poisons it. The store
+    /// only changes through its own all-or-nothing writes, so the poison is
+    /// cleared instead of failing every later request.
+    fn read(&self) -> RwLockReadGuard<'_, S> {
+        self.database.read().unwrap_or_else(|poisoned| {
+            self.database.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, S> {
+        self.database.write().unwrap_or_else(|poisoned| {
+            self.database.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+    /// Returns the requested page together with the validators of exactly
+    /// that representation, so equal queries over unchanged users match.
+    pub fn show_users(&self, query: &Query) -> Result<(Page<String>, Validators), ApiError> {
+        let sort = parse_sort(query)?;
+        let fields = parse_fields(query)?;
+        let page = PageRequest::from_query(query, &sort)?;
+        let filter = parse_filter(query)?;
+
+        let database = self.read();
+        let page = database.page(&filter, &sort, &page)?;
+        let json = match fields {
+            Some(fields) => {
+                let users: Vec<_> = page
+                    .items
+                    .iter()
+                    .map(|user| project(user, &fields))
+                    .collect::<Result<_, _>>()?;
+                serde_json::to_string(&users)
+            }
+            None => serde_json::to_string(&page.items),
+        }
+        .map_err(|_| ApiError::internal())?;
+        let ids: Vec<u32> = page.items.iter().map(|user| user.id).collect();
+        let page = page.map(|_| json);
+
+        let metas = ids
+            .into_iter()
+            .map(|id| Ok((id, database.get_meta(id)?.unwrap_or_default())))
+            .collect::<Result<Vec<_>, ApiError>>()?;
+        let validators = Validators::for_listing(
+            &metas,
+            page.total,
+            &query.to_string(),
+            database.last_modified()?,
+        );
+        Ok((page, validators))
     }
 
-    pub fn show_user(&self, id: u32) -> Result<String, Errors> {
-        let mut users = self.database.lock().map_err(|_| Errors::ServerError(500))?;
-        let user = users.get_one(id)?;
-        let json = serde_json::to_string(user).map_err(|_| Errors::ServerError(500));
-        json
+    pub fn show_user(&self, id: u32, query: &Query) -> Result<(String, Validators), ApiError> {
+        let fields = parse_fields(query)?;
+
+        let database = self.read();
+        let user = database.get_one(id)?;
+        let json = match fields {
+            Some(fields) => serde_json::to_string(&project(&user, &fields)?),
+            None => serde_json::to_string(&user),
+        }
+        .map_err(|_| ApiError::internal())?;
+        let meta = database.get_meta(id)?.unwrap_or_default();
+        Ok((json, Validators::for_user(&meta)))
     }
 
     pub fn add_user(
         &self,
         data: HashMap<String, String>,
         new_id: Option<u32>,
-    ) -> Result<String, Errors> {
-        let mut users = self.database.lock().map_err(|_| Errors::ServerError(500))?;
-        if data.contains_key("name")
-            && data.contains_key("lastname")
-            && data.contains_key("birth_year")
-            && data.contains_key("group")
-        {
-            let group = match data.get("group").unwrap().as_str() {
-                "user" => UserGroup::User,
-                "premium" => UserGroup::Premium,
-                "admin" => UserGroup::Admin,
-                _ => return Err(Errors::UserError(400)),
-            };
-            let user = User {
-                id: 0,
-                name: data.get("name").unwrap().to_owned(),
-                lastname: data.get("lastname").unwrap().to_owned(),
-                birth_year: data
-                    .get("birth_year")
-                    .unwrap()
-                    .parse()
-                    .map_err(|_| Errors::UserError(400))?,
-                group,
-            };
-            let id = users.add_entry(user, new_id);
+    ) -> Result<String, ApiError> {
+        let user = parse_user(&data)?;
+
+        let mut users = self.write();
+        let id = users.add_entry(user, new_id)?;
 
-            Ok(format!("{}", id))
+        Ok(format!("{}", id))
+    }
+
+    /// Replaces every field of user `id`, creating it under that id when it
+    /// does not exist yet. Returns whether it was created and the stored user.
+    /// With `require_if_match` set only the creation may go unconditional.
+    pub fn replace_user(
+        &self,
+        id: u32,
+        data: HashMap<String, String>,
+        precondition: &Precondition,
+        require_if_match: bool,
+    ) -> Result<(bool, String), ApiError> {
+        let user = parse_user(&data)?;
+
+        let mut users = self.write();
+        let etag = users
+            .get_meta(id)?
+            .map(|meta| Validators::for_user(&meta).etag);
+        if require_if_match {
+            conditional::require_for_existing(precondition, etag.as_deref())?;
+        }
+        precondition.check(etag.as_deref())?;
+        let created = etag.is_none();
+        if created {
+            users.add_entry(user, Some(id))?;
         } else {
-            Err(Errors::UserError(400))
+            let data = vec![
+                UserEnum::Name(user.name),
+                UserEnum::Lastname(user.lastname),
+                UserEnum::BirthYear(user.birth_year),
+                UserEnum::Group(user.group),
+            ];
+            users.change_user(id, data)?;
         }
+        let user = serde_json::to_string(&users.get_one(id)?).map_err(|_| ApiError::internal())?;
+        Ok((created, user))
     }
 
     pub fn change_user_data(
         &self,
         id: u32,
         change_data: HashMap<String, String>,
-    ) -> Result<String, Errors> {
-        let mut users = self.database.lock().map_err(|_| Errors::ServerError(500))?;
-
-        let mut change_data_enums = Vec::new();
-        for (key, value) in change_data {
-            let data_enum = match key.as_str() {
-                "name" => UserEnum::Name(value.to_owned()),
-                "lastname" => UserEnum::Lastname(value.to_owned()),
-                "birth_year" => {
-                    UserEnum::BirthYear(value.parse().map_err(|_| Errors::UserError(400))?)
-                }
-                "group" => {
-                    let group = match value.as_str() {
-                        "user" => UserGroup::User,
-                        "premium" => UserGroup::Premium,
-                        "admin" => UserGroup::Admin,
-                        _ => return Err(Errors::UserError(400)),
-                    };
-                    UserEnum::Group(group)
-                }
-                _ => {
-                    return Err(Errors::UserError(400));
-                }
-            };
-            change_data_enums.push(data_enum);
-        }
-        users.change_user(id, change_data_enums)?;
+        precondition: &Precondition,
+    ) -> Result<String, ApiError> {
+        let data = parse_changes(change_data)?;
+
+        let mut users = self.write();
+        check_precondition(&*users, id, precondition)?;
+        users.change_user(id, data)?;
         Ok("Changed".to_string())
     }
 
-    pub fn delete_user(&self, id: u32) -> Result<String, Errors> {
-        let mut users = self.database.lock().map_err(|_| Errors::ServerError(500))?;
+    /// Deleting is idempotent: repeating the call leaves the store unchanged
+    /// and reports the user as not found once it is gone.
+    pub fn delete_user(&self, id: u32, precondition: &Precondition) -> Result<String, ApiError> {
+        let mut users = self.write();
+        check_precondition(&*users, id, precondition)?;
         users.remove_entry(id)?;
         Ok("Removed user".to_string())
     }
+
+    /// Applies every operation of a `POST /transactions` body or none. The
+    /// `If-Match` conditions are checked against the users as they were
+    /// before the transaction, under the same write lock as the commit, so
+    /// no other request sees or slips into a half applied batch.
+    pub fn commit_transaction(
+        &self,
+        body: &[u8],
+        require_if_match: bool,
+    ) -> Result<String, ApiError> {
+        let body: TransactionBody = serde_json::from_slice(body).map_err(|error| {
+            ApiError::bad_request("invalid_json", format!("Invalid transaction: {error}"))
+        })?;
+        if body.operations.len() > MAX_OPERATIONS {
+            return Err(ApiError::invalid_field(
+                "operations",
+                "too_many_operations",
+                format!("A transaction can hold at most {MAX_OPERATIONS} operations"),
+            ));
+        }
+
+        let mut transaction = Transaction::begin();
+        let mut preconditions = Vec::new();
+        for (index, operation) in body.operations.into_iter().enumerate() {
+            if let Some((id, precondition)) = stage(&mut transaction, operation, require_if_match)
+                .map_err(|error| error.in_operation(index))?
+            {
+                preconditions.push((index, id, precondition));
+            }
+        }
+
+        let mut users = self.write();
+        for (index, id, precondition) in &preconditions {
+            check_precondition(&*users, *id, precondition)
+                .map_err(|error| error.in_operation(*index))?;
+        }
+        let ids = transaction.commit(&mut *users)?;
+        serde_json::to_string(&ids).map_err(|_| ApiError::internal())
+    }
+
+    /// Folds the store's log into a fresh snapshot, see [`UserStore::compact`].
+    pub fn compact(&self) -> Result<(), ApiError> {
+        let mut users = self.write();
+        users.compact()
+    }
+}
+
+/// Upper bound for the operations of one transaction.
+pub const MAX_OPERATIONS: usize = 1000;
+
+#[derive(Deserialize)]
+struct TransactionBody {
+    operations: Vec<OperationBody>,
+}
+
+/// One operation of a `POST /transactions` body. Users are given as string
+/// maps, like in the bodies of `POST` and `PATCH /users`.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum OperationBody {
+    Add {
+        user: HashMap<String, String>,
+    },
+    Change {
+        id: u32,
+        user: HashMap<String, String>,
+        if_match: Option<String>,
+    },
+    Remove {
+        id: u32,
+        if_match: Option<String>,
+    },
+}
+
+/// Validates `operation` and stages it, returning the condition it carries.
+fn stage(
+    transaction: &mut Transaction,
+    operation: OperationBody,
+    require_if_match: bool,
+) -> Result<Option<(u32, Precondition)>, ApiError> {
+    match operation {
+        OperationBody::Add { mut user } => {
+            let id = take_id(&mut user)?;
+            transaction.add(parse_user(&user)?, id);
+            Ok(None)
+        }
+        OperationBody::Change { id, user, if_match } => {
+            let precondition = Precondition::parse(if_match.as_deref(), require_if_match)?;
+            transaction.change(id, parse_changes(user)?);
+            Ok(Some((id, precondition)))
+        }
+        OperationBody::Remove { id, if_match } => {
+            let precondition = Precondition::parse(if_match.as_deref(), require_if_match)?;
+            transaction.remove(id);
+            Ok(Some((id, precondition)))
+        }
+    }
+}
+
+/// Compares `If-Match` with the user's current ETag. The caller holds the
+/// lock until the write is done, so no other write can slip in between.
+fn check_precondition<S: UserStore + ?Sized>(
+    database: &S,
+    id: u32,
+    precondition: &Precondition,
+) -> Result<(), ApiError> {
+    let etag = database
+        .get_meta(id)?
+        .map(|meta| Validators::for_user(&meta).etag);
+    precondition.check(etag.as_deref())
+}
+
+/// Removes an explicit `id` from a request body, for clients that mirror
+/// ids from another system.
+pub fn take_id(data: &mut HashMap<String, String>) -> Result<Option<u32>, ApiError> {
+    data.remove("id")
+        .map(|id| {
+            id.parse().map_err(|_| {
+                ApiError::invalid_field(
+                    "id",
+                    "not_a_number",
+                    format!("id must be a number, got '{id}'"),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// Validates a complete user, reporting every missing or invalid field.
+fn parse_user(data: &HashMap<String, String>) -> Result<User, ApiError> {
+    let mut errors: Vec<FieldError> = ["name", "lastname", "birth_year", "group"]
+        .into_iter()
+        .filter(|field| !data.contains_key(*field))
+        .map(|field| FieldError::new(field, "missing", format!("{field} is required")))
+        .collect();
+    let birth_year = data.get("birth_year").map(|year| parse_birth_year(year));
+    let group = data.get("group").map(|group| parse_group(group));
+    if let Some(Err(error)) = &birth_year {
+        errors.push(error.clone());
+    }
+    if let Some(Err(error)) = &group {
+        errors.push(error.clone());
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::invalid_fields(errors));
+    }
+
+    Ok(User {
+        id: 0,
+        name: data.get("name").unwrap().to_owned(),
+        lastname: data.get("lastname").unwrap().to_owned(),
+        birth_year: birth_year.unwrap().unwrap(),
+        group: group.unwrap().unwrap(),
+    })
+}
+
+/// Parses the fields of a partial update, reporting every invalid one.
+fn parse_changes(data: HashMap<String, String>) -> Result<Vec<UserEnum>, ApiError> {
+    let mut changes = Vec::new();
+    let mut errors = Vec::new();
+    // Map order is random, sorting keeps the changes and the reported errors
+    // in the same order for equal requests.
+    let mut data: Vec<_> = data.into_iter().collect();
+    data.sort();
+    for (key, value) in data {
+        let change = match key.as_str() {
+            "name" => Ok(UserEnum::Name(value.to_owned())),
+            "lastname" => Ok(UserEnum::Lastname(value.to_owned())),
+            "birth_year" => parse_birth_year(&value).map(UserEnum::BirthYear),
+            "group" => parse_group(&value).map(UserEnum::Group),
+            _ => Err(FieldError::new(
+                &key,
+                "unknown_field",
+                format!("Unknown field '{key}'"),
+            )),
+        };
+        match change {
+            Ok(change) => changes.push(change),
+            Err(error) => errors.push(error),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::invalid_fields(errors));
+    }
+    Ok(changes)
+}
+
+fn parse_group(group: &str) -> Result<UserGroup, FieldError> {
+    match group {
+        "user" => Ok(UserGroup::User),
+        "premium" => Ok(UserGroup::Premium),
+        "admin" => Ok(UserGroup::Admin),
+        _ => Err(FieldError::new(
+            "group",
+            "unknown_value",
+            format!("Unknown group '{group}', expected one of: user, premium, admin"),
+        )),
+    }
+}
+
+fn parse_birth_year(year: &str) -> Result<u16, FieldError> {
+    year.parse().map_err(|_| {
+        FieldError::new(
+            "birth_year",
+            "not_a_number",
+            format!("birth_year must be a number, got '{year}'"),
+        )
+    })
+}
+
+/// Parses `sort=-birth_year,lastname`, a leading `-` sorts descending.
+fn parse_sort(query: &Query) -> Result<Vec<SortKey>, ApiError> {
+    let Some(sort) = query.get("sort") else {
+        return Ok(Vec::new());
+    };
+    sort.split(',')
+        .map(|key| {
+            let (name, descending) = match key.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (key, false),
+            };
+            let field = UserField::parse(name).ok_or_else(|| unknown_field("sort", name))?;
+            Ok(SortKey { field, descending })
+        })
+        .collect()
+}
+
+/// Parses `fields=id,name`, `None` means every field is returned.
+fn parse_fields(query: &Query) -> Result<Option<Vec<UserField>>, ApiError> {
+    query
+        .get("fields")
+        .map(|fields| {
+            fields
+                .split(',')
+                .map(|name| UserField::parse(name).ok_or_else(|| unknown_field("fields", name)))
+                .collect()
+        })
+        .transpose()
+}
+
+fn unknown_field(parameter: &str, name: &str) -> ApiError {
+    ApiError::invalid_field(
+        parameter,
+        "unknown_field",
+        format!("Unknown user field '{name}'"),
+    )
+}
+
+fn project(user: &User, fields: &[UserField]) -> Result<Value, ApiError> {
+    let Value::Object(mut object) = serde_json::to_value(user).map_err(|_| ApiError::internal())?
+    else {
+        return Err(ApiError::internal());
+    };
+    object.retain(|key, _| fields.iter().any(|field| field.name() == key));
+    Ok(Value::Object(object))
+}
+
+fn parse_filter(query: &Query) -> Result<UserFilter, ApiError> {
+    let year = |name: &str| {
+        query
+            .get(name)
+            .map(|year| {
+                year.parse::<u16>().map_err(|_| {
+                    ApiError::invalid_field(
+                        name,
+                        "not_a_number",
+                        format!("{name} must be a number, got '{year}'"),
+                    )
+                })
+            })
+            .transpose()
+    };
+    Ok(UserFilter {
+        group: query.get("group").map(parse_group).transpose()?,
+        birth_year: year("birth_year")?,
+        birth_year_gte: year("birth_year_gte")?,
+        birth_year_lte: year("birth_year_lte")?,
+        name_prefix: query.get("name_prefix").map(str::to_string),
+        lastname_prefix: query.get("lastname_prefix").map(str::to_string),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db_mock::{DataBaseMock, MockCalls};
+    use crate::{
+        db_mock::{DataBaseMock, MockCalls},
+        transaction::Operation,
+    };
 
-    fn create_user(id: u32) -> User {
-        User {
-            id,
-            name: "test".to_string(),
-            lastname: "test1".to_string(),
-            birth_year: 2000,
-            group: crate::UserGroup::Premium,
-        }
-    }
-    fn create_db() -> (Vec<User>, Arc<Mutex<DataObjectEnum>>) {
+    fn create_db() -> (Vec<User>, Arc<RwLock<DataBaseMock>>) {
         let user_1 = User {
             id: 1,
             name: "Hlib".to_string(),
@@ -143,53 +469,112 @@ mod tests {
             group: crate::UserGroup::User,
         };
         let users = vec![user_1, user_2];
-        let db = Arc::new(Mutex::new(DataObjectEnum::DataBaseMock(DataBaseMock::new(
-            users.clone(),
-        ))));
+        let db = Arc::new(RwLock::new(DataBaseMock::new(users.clone())));
 
         (users, db)
     }
-    fn create_controller(db: Arc<Mutex<DataObjectEnum>>) -> UserController {
+    fn create_controller(db: Arc<RwLock<DataBaseMock>>) -> UserController<DataBaseMock> {
         UserController::new(db)
     }
     #[test]
+    fn test_reads_run_in_parallel() {
+        let (users, db) = create_db();
+        let controller = create_controller(Arc::clone(&db));
+        let _reading = db.read().unwrap();
+
+        let (user, _) = std::thread::spawn(move || controller.show_user(1, &Query::default()))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(user, serde_json::to_string(&users[0]).unwrap());
+    }
+    #[test]
     fn test_show_users() {
         let (_, db) = create_db();
         let controller = create_controller(db);
-        controller.show_users().unwrap();
+        controller
+            .show_users(&Query::parse("group=admin&birth_year_gte=1990"))
+            .unwrap();
 
-        let mock = match controller.database.lock().unwrap().to_owned() {
-            DataObjectEnum::DataBaseMock(database_mock) => database_mock,
-            _ => panic!("error"),
-        };
+        let calls = controller.database.read().unwrap().calls();
 
-        let call_id = mock
-            .calls
+        let call_id = calls
             .iter()
-            .position(|call| matches!(call, MockCalls::GetAll))
+            .position(|call| matches!(call, MockCalls::Query { filter: _ }))
+            .unwrap();
+        let call = calls.get(call_id).unwrap();
+
+        let filter = UserFilter {
+            group: Some(UserGroup::Admin),
+            birth_year_gte: Some(1990),
+            ..Default::default()
+        };
+        assert_eq!(*call, MockCalls::Query { filter });
+    }
+
+    #[test]
+    fn test_show_users_invalid_filter() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+
+        let error = controller
+            .show_users(&Query::parse("group=owner"))
+            .unwrap_err();
+        assert_eq!(error.status, 400);
+        assert_eq!(error.fields[0].field, "group");
+        assert_eq!(error.fields[0].code, "unknown_value");
+
+        let error = controller
+            .show_users(&Query::parse("birth_year_lte=old"))
+            .unwrap_err();
+        assert_eq!(error.status, 400);
+        assert_eq!(error.fields[0].field, "birth_year_lte");
+        assert_eq!(error.fields[0].code, "not_a_number");
+    }
+
+    #[test]
+    fn test_show_users_sorted_and_projected() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let page = controller
+            .show_users(&Query::parse("sort=-group&fields=id,group"))
             .unwrap();
-        let call = mock.calls.get(call_id).unwrap();
 
-        assert_eq!(*call, MockCalls::GetAll);
+        assert_eq!(
+            page.0.items,
+            r#"[{"group":"User","id":2},{"group":"Admin","id":1}]"#
+        );
+    }
+
+    #[test]
+    fn test_unknown_sort_or_fields() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+
+        for query in ["sort=age", "sort=name,", "fields=id,password", "fields="] {
+            let error = controller.show_users(&Query::parse(query)).unwrap_err();
+            assert_eq!(error.status, 400, "{query}");
+            assert_eq!(error.fields[0].code, "unknown_field", "{query}");
+        }
+        let error = controller
+            .show_user(1, &Query::parse("fields=age"))
+            .unwrap_err();
+        assert_eq!(error.fields[0].code, "unknown_field");
     }
 
     #[test]
     fn test_show_user() {
         let (_, db) = create_db();
         let controller = create_controller(db);
-        controller.show_user(1).unwrap();
+        controller.show_user(1, &Query::default()).unwrap();
 
-        let mock = match controller.database.lock().unwrap().to_owned() {
-            DataObjectEnum::DataBaseMock(database_mock) => database_mock,
-            _ => panic!("error"),
-        };
+        let calls = controller.database.read().unwrap().calls();
 
-        let call_id = mock
-            .calls
+        let call_id = calls
             .iter()
             .position(|call| matches!(call, MockCalls::GetOne { id: _ }))
             .unwrap();
-        let call = mock.calls.get(call_id).unwrap();
+        let call = calls.get(call_id).unwrap();
         assert_eq!(*call, MockCalls::GetOne { id: 1 });
     }
 
@@ -205,17 +590,13 @@ mod tests {
         ]);
         controller.add_user(data, None).unwrap();
 
-        let mock = match controller.database.lock().unwrap().to_owned() {
-            DataObjectEnum::DataBaseMock(database_mock) => database_mock,
-            _ => panic!("error"),
-        };
+        let calls = controller.database.read().unwrap().calls();
 
-        let call_id = mock
-            .calls
+        let call_id = calls
             .iter()
             .position(|call| matches!(call, MockCalls::AddEntry { user: _, new_id: _ }))
             .unwrap();
-        let call = mock.calls.get(call_id).unwrap();
+        let call = calls.get(call_id).unwrap();
 
         let user = User {
             id: 0,
@@ -229,25 +610,119 @@ mod tests {
         assert_eq!(*call, expected_call);
     }
 
+    #[test]
+    fn test_take_id() {
+        let mut data = HashMap::from([("id".to_string(), "7".to_string())]);
+        assert_eq!(take_id(&mut data), Ok(Some(7)));
+        assert!(data.is_empty());
+        assert_eq!(take_id(&mut data), Ok(None));
+
+        let mut data = HashMap::from([("id".to_string(), "seven".to_string())]);
+        assert_eq!(
+            take_id(&mut data).unwrap_err().fields[0].code,
+            "not_a_number"
+        );
+    }
+
+    #[test]
+    fn test_add_user_reports_every_invalid_field() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let data = HashMap::from([
+            ("name".to_string(), "test".to_string()),
+            ("birth_year".to_string(), "soon".to_string()),
+            ("group".to_string(), "owner".to_string()),
+        ]);
+        let error = controller.add_user(data, None).unwrap_err();
+
+        let fields: Vec<_> = error
+            .fields
+            .iter()
+            .map(|field| (field.field.as_str(), field.code))
+            .collect();
+        assert_eq!(error.status, 400);
+        assert_eq!(
+            fields,
+            vec![
+                ("lastname", "missing"),
+                ("birth_year", "not_a_number"),
+                ("group", "unknown_value"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_existing_user() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let data = HashMap::from([
+            ("name".to_string(), "test".to_string()),
+            ("lastname".to_string(), "test1".to_string()),
+            ("birth_year".to_string(), "1999".to_string()),
+            ("group".to_string(), "user".to_string()),
+        ]);
+        let (created, _) = controller
+            .replace_user(1, data, &Precondition::None, false)
+            .unwrap();
+
+        let calls = controller.database.read().unwrap().calls();
+        assert!(!created);
+        assert_eq!(
+            calls[0],
+            MockCalls::ChangeUser {
+                id: 1,
+                data: vec![
+                    UserEnum::Name("test".to_string()),
+                    UserEnum::Lastname("test1".to_string()),
+                    UserEnum::BirthYear(1999),
+                    UserEnum::Group(UserGroup::User),
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_replace_user_requires_every_field() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let data = HashMap::from([("name".to_string(), "test".to_string())]);
+        let error = controller
+            .replace_user(1, data, &Precondition::None, false)
+            .unwrap_err();
+
+        assert_eq!(error.status, 400);
+        assert_eq!(error.fields.len(), 3);
+    }
+
+    #[test]
+    fn test_change_user_unknown_field() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let change_data = HashMap::from([("age".to_string(), "20".to_string())]);
+        let error = controller
+            .change_user_data(1, change_data, &Precondition::None)
+            .unwrap_err();
+
+        assert_eq!(error.status, 400);
+        assert_eq!(error.fields[0].field, "age");
+        assert_eq!(error.fields[0].code, "unknown_field");
+    }
+
     #[test]
     fn test_change_user_name() {
         let (_, db) = create_db();
         let controller = create_controller(db.clone());
 
         let change_data = HashMap::from([("name".to_string(), "test".to_string())]);
-        let result = controller.change_user_data(1, change_data);
+        let result = controller.change_user_data(1, change_data, &Precondition::None);
 
-        let mock = match controller.database.lock().unwrap().to_owned() {
-            DataObjectEnum::DataBaseMock(database_mock) => database_mock,
-            _ => panic!("error"),
-        };
+        let calls = controller.database.read().unwrap().calls();
 
-        let call_id = mock
-            .calls
+        let call_id = calls
             .iter()
             .position(|call| matches!(call, MockCalls::ChangeUser { id: _, data: _ }))
             .unwrap();
-        let call = mock.calls.get(call_id).unwrap();
+        let call = calls.get(call_id).unwrap();
 
         let change_data_enum = vec![UserEnum::Name("test".to_string())];
         assert_eq!(result, Ok("Changed".to_string()));
@@ -269,19 +744,15 @@ mod tests {
             ("group".to_string(), "premium".to_string()),
             ("birth_year".to_string(), "2009".to_string()),
         ]);
-        let result = controller.change_user_data(1, change_data);
+        let result = controller.change_user_data(1, change_data, &Precondition::None);
 
-        let mock = match controller.database.lock().unwrap().to_owned() {
-            DataObjectEnum::DataBaseMock(database_mock) => database_mock,
-            _ => panic!("error"),
-        };
+        let calls = controller.database.read().unwrap().calls();
 
-        let call_id = mock
-            .calls
+        let call_id = calls
             .iter()
             .position(|call| matches!(call, MockCalls::ChangeUser { id: _, data: _ }))
             .unwrap();
-        let call = mock.calls.get(call_id).unwrap();
+        let call = calls.get(call_id).unwrap();
 
         let change_data_enum = vec![
             UserEnum::BirthYear(2009),
@@ -297,25 +768,114 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_stale_precondition_blocks_writes() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let stale = Precondition::Tags(vec!["\"v7\"".to_string()]);
+        let change_data = HashMap::from([("name".to_string(), "test".to_string())]);
+
+        let error = controller
+            .change_user_data(1, change_data, &stale)
+            .unwrap_err();
+        assert_eq!(error.status, 412);
+        assert_eq!(controller.delete_user(1, &stale).unwrap_err().status, 412);
+
+        let calls = controller.database.read().unwrap().calls();
+        assert!(calls.is_empty());
+
+        let current = Precondition::Tags(vec!["\"v0\"".to_string()]);
+        assert_eq!(
+            controller.delete_user(1, &current),
+            Ok("Removed user".to_string())
+        );
+    }
+
     #[test]
     fn test_delete_user() {
         let (_, db) = create_db();
         let controller = create_controller(db.clone());
-        let result = controller.delete_user(2);
+        let result = controller.delete_user(2, &Precondition::None);
 
-        let mock = match controller.database.lock().unwrap().to_owned() {
-            DataObjectEnum::DataBaseMock(database_mock) => database_mock,
-            _ => panic!("error"),
-        };
+        let calls = controller.database.read().unwrap().calls();
 
-        let call_id = mock
-            .calls
+        let call_id = calls
             .iter()
             .position(|call| matches!(call, MockCalls::RemoveEntry { id: _ }))
             .unwrap();
-        let call = mock.calls.get(call_id).unwrap();
+        let call = calls.get(call_id).unwrap();
 
         assert_eq!(result, Ok("Removed user".to_string()));
         assert_eq!(*call, MockCalls::RemoveEntry { id: 2 })
     }
+
+    #[test]
+    fn test_commit_transaction() {
+        let (users, db) = create_db();
+        let controller = create_controller(db);
+        let body = serde_json::json!({"operations": [
+            {"op": "add", "user": {"id": "5", "name": "Jan", "lastname": "Nowak", "birth_year": "1990", "group": "user"}},
+            {"op": "change", "id": 1, "user": {"group": "premium"}, "if_match": "\"v0\""},
+            {"op": "remove", "id": 2},
+        ]});
+        let result = controller.commit_transaction(body.to_string().as_bytes(), false);
+
+        assert_eq!(result, Ok("[5,1,2]".to_string()));
+        let calls = controller.database.read().unwrap().calls();
+        let user = User {
+            id: 0,
+            name: "Jan".to_string(),
+            lastname: "Nowak".to_string(),
+            birth_year: 1990,
+            group: UserGroup::User,
+        };
+        assert_eq!(
+            calls,
+            vec![MockCalls::Commit {
+                operations: vec![
+                    Operation::Add { user, id: Some(5) },
+                    Operation::Change {
+                        id: 1,
+                        data: vec![UserEnum::Group(UserGroup::Premium)]
+                    },
+                    Operation::Remove { id: users[1].id },
+                ]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_commit_transaction_rejects_invalid_operations() {
+        let (_, db) = create_db();
+        let controller = create_controller(db);
+        let commit = |body: serde_json::Value, require_if_match| {
+            controller
+                .commit_transaction(body.to_string().as_bytes(), require_if_match)
+                .unwrap_err()
+        };
+
+        let error = commit(
+            serde_json::json!({"operations": [
+                {"op": "remove", "id": 2},
+                {"op": "change", "id": 1, "user": {"birth_year": "soon"}},
+            ]}),
+            false,
+        );
+        assert_eq!(error.status, 400);
+        assert_eq!(error.fields[0].field, "operations[1].birth_year");
+
+        let error = commit(
+            serde_json::json!({"operations": [{"op": "remove", "id": 2}]}),
+            true,
+        );
+        assert_eq!(error.status, 428);
+        assert_eq!(error.fields[0].field, "operations[0]");
+
+        let error = commit(
+            serde_json::json!({"operations": [{"op": "rename", "id": 2}]}),
+            false,
+        );
+        assert_eq!(error.code, "invalid_json");
+        assert!(controller.database.read().unwrap().calls().is_empty());
+    }
 }
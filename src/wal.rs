@@ -0,0 +1,375 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{db_object::UserEnum, User};
+
+/// Every record starts with the payload length and its CRC32, both little
+/// endian, followed by the JSON encoded [`LogEntry`].
+const HEADER_SIZE: usize = 8;
+/// Larger lengths can only come from a damaged header.
+const MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
+
+/// One mutation of the users store, as written to the log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LogEntry {
+    Add {
+        user: User,
+    },
+    Change {
+        id: u32,
+        data: Vec<UserEnum>,
+    },
+    Remove {
+        id: u32,
+    },
+    /// Entries of one transaction, replayed together or not at all.
+    Batch {
+        entries: Vec<LogEntry>,
+    },
+    /// First entry of a log that continues the snapshot `generation`.
+    Checkpoint {
+        generation: u64,
+    },
+}
+
+/// An append-only file of [`LogEntry`] records.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+    /// Length of the valid part of the file, where the next record goes.
+    len: u64,
+    records: usize,
+}
+
+impl WriteAheadLog {
+    /// Opens or creates the log at `path` and returns the entries it holds.
+    ///
+    /// A torn final record, left behind by a crash in the middle of an
+    /// append, was never acknowledged and is cut off. A damaged record
+    /// followed by further records is reported as `InvalidData` instead,
+    /// since dropping it would silently lose acknowledged writes.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<LogEntry>)> {
+        let path = path.as_ref();
+        let created = !path.exists();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // Otherwise the new file, and every record acknowledged in it, may
+        // be gone after a power loss.
+        if created {
+            sync_parent(path)?;
+        }
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match decode(&bytes[offset..])? {
+                Some((entry, size)) => {
+                    entries.push(entry);
+                    offset += size;
+                }
+                None => break,
+            }
+        }
+
+        let len = offset as u64;
+        if len < bytes.len() as u64 {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+        let records = entries.len();
+        Ok((Self { file, len, records }, entries))
+    }
+
+    /// Appends `entry` and waits until it reached the disk. On failure the
+    /// partially written record is cut off again, so the log stays valid.
+    /// Entries that `open` would not read back fail with `InvalidInput`
+    /// before anything is written.
+    pub fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        let record = encode(entry)?;
+        if record.len() - HEADER_SIZE > MAX_RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record larger than the log allows",
+            ));
+        }
+        let result = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data());
+        if let Err(error) = result {
+            let _ = self.file.set_len(self.len);
+            let _ = self.file.seek(SeekFrom::Start(self.len));
+            return Err(error);
+        }
+        self.len += record.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Drops every record and starts over with `first`.
+    pub fn reset(&mut self, first: &LogEntry) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.len = 0;
+        self.records = 0;
+        self.append(first)
+    }
+
+    /// Number of records in the log.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// Size of the log in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+fn encode(entry: &LogEntry) -> io::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(entry)?;
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Decodes the record at the start of `bytes` and returns it with its size,
+/// or `None` when `bytes` only holds a torn final record.
+fn decode(bytes: &[u8]) -> io::Result<Option<(LogEntry, usize)>> {
+    let Some(header) = bytes.get(..HEADER_SIZE) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    // No entry encodes to nothing, an empty payload only comes from zeros.
+    if len == 0 || len > MAX_RECORD_SIZE {
+        return torn_tail(bytes, 0, "record header with an impossible length");
+    }
+    let size = HEADER_SIZE + len;
+    let Some(payload) = bytes.get(HEADER_SIZE..size) else {
+        return Ok(None);
+    };
+
+    if crc32fast::hash(payload) != crc {
+        return torn_tail(
+            bytes,
+            size,
+            "record with a bad checksum in the middle of the log",
+        );
+    }
+    match serde_json::from_slice(payload) {
+        Ok(entry) => Ok(Some((entry, size))),
+        Err(_) => torn_tail(
+            bytes,
+            size,
+            "record with a valid checksum could not be decoded",
+        ),
+    }
+}
+
+/// A damaged record is a torn final one when only zeros follow it: a crash
+/// after the file grew but before its data reached the disk leaves those.
+fn torn_tail<T>(bytes: &[u8], size: usize, message: &str) -> io::Result<Option<T>> {
+    if bytes[size..].iter().all(|&byte| byte == 0) {
+        Ok(None)
+    } else {
+        Err(invalid_data(message))
+    }
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Makes a file created or renamed at `path` durable, which needs its
+/// directory entry on disk too.
+#[cfg(unix)]
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::UserGroup;
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A fresh path in the temp directory, unique within the test run.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("rust_api_{}_{name}_{id}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn entries() -> Vec<LogEntry> {
+        vec![
+            LogEntry::Add {
+                user: User {
+                    id: 1,
+                    name: "Hlib".to_string(),
+                    lastname: "Shutov".to_string(),
+                    birth_year: 2000,
+                    group: UserGroup::Admin,
+                },
+            },
+            LogEntry::Change {
+                id: 1,
+                data: vec![UserEnum::BirthYear(1999)],
+            },
+            LogEntry::Remove { id: 1 },
+        ]
+    }
+
+    fn write_entries(path: &Path) -> u64 {
+        let (mut log, _) = WriteAheadLog::open(path).unwrap();
+        for entry in entries() {
+            log.append(&entry).unwrap();
+        }
+        log.len()
+    }
+
+    #[test]
+    fn test_replays_appended_entries() {
+        let path = temp_path("replay");
+        let len = write_entries(&path);
+
+        let (log, replayed) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(replayed, entries());
+        assert_eq!(log.len(), len);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_reset() {
+        let path = temp_path("reset");
+        write_entries(&path);
+        let (mut log, _) = WriteAheadLog::open(&path).unwrap();
+        let checkpoint = LogEntry::Checkpoint { generation: 3 };
+        log.reset(&checkpoint).unwrap();
+        log.append(&entries()[0]).unwrap();
+        assert_eq!(log.records(), 2);
+        drop(log);
+
+        let (_, replayed) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(replayed, vec![checkpoint, entries()[0].clone()]);
+    }
+
+    #[test]
+    fn test_oversized_record_is_not_written() {
+        let path = temp_path("oversized");
+        let len = write_entries(&path);
+        let (mut log, _) = WriteAheadLog::open(&path).unwrap();
+        let LogEntry::Add { mut user } = entries().remove(0) else {
+            unreachable!()
+        };
+        user.name = "a".repeat(MAX_RECORD_SIZE);
+
+        let error = log.append(&LogEntry::Add { user }).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(log.len(), len);
+        drop(log);
+        assert_eq!(WriteAheadLog::open(&path).unwrap().1, entries());
+    }
+
+    #[test]
+    fn test_torn_final_record_is_cut_off() {
+        let path = temp_path("torn");
+        let len = write_entries(&path);
+        let full = fs::read(&path).unwrap();
+        let last = encode(&entries()[2]).unwrap().len() as u64;
+
+        // A crash may stop an append anywhere within the record.
+        for cut in [len - last + 3, len - last + HEADER_SIZE as u64 + 2, len - 1] {
+            fs::write(&path, &full[..cut as usize]).unwrap();
+
+            let (mut log, replayed) = WriteAheadLog::open(&path).unwrap();
+            assert_eq!(replayed, entries()[..2]);
+            assert_eq!(fs::metadata(&path).unwrap().len(), len - last);
+
+            log.append(&entries()[2]).unwrap();
+            drop(log);
+            assert_eq!(WriteAheadLog::open(&path).unwrap().1, entries());
+        }
+    }
+
+    #[test]
+    fn test_zero_filled_tail_is_cut_off() {
+        let path = temp_path("zero_tail");
+        let len = write_entries(&path);
+        let full = fs::read(&path).unwrap();
+        let last = encode(&entries()[2]).unwrap().len() as u64;
+
+        // Zeros after the last record, or in place of its payload.
+        let torn = len - last + HEADER_SIZE as u64;
+        for (cut, kept, valid_len) in [(len, 3, len), (torn, 2, len - last)] {
+            let mut bytes = full[..cut as usize].to_vec();
+            bytes.resize(len as usize + 64, 0);
+            fs::write(&path, &bytes).unwrap();
+
+            let (_, replayed) = WriteAheadLog::open(&path).unwrap();
+            assert_eq!(replayed, entries()[..kept]);
+            assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+        }
+    }
+
+    #[test]
+    fn test_bad_checksum_of_final_record_is_cut_off() {
+        let path = temp_path("tail_checksum");
+        let len = write_entries(&path);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[len as usize - 2] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let (_, replayed) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(replayed, entries()[..2]);
+    }
+
+    #[test]
+    fn test_corruption_before_the_end_is_an_error() {
+        let path = temp_path("corrupt");
+        write_entries(&path);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + 2] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let error = WriteAheadLog::open(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+}
@@ -1,14 +1,24 @@
-use rust_api::{db_object::DataBase, db_object_enum::DataObjectEnum, run_server, User, UserGroup};
+use rust_api::{
+    db_object::{DataBase, RecordMeta, UserEnum, UserFilter},
+    durable::DurableDataBase,
+    error::ApiError,
+    request::MAX_HEADERS_SIZE,
+    response::Response,
+    sqlite::SqliteStore,
+    store::UserStore,
+    users_router, OverloadPolicy, QueueStats, Server, ServerConfig, ServerCore, ServerHandle,
+    ThreadPool, User, UserGroup,
+};
 use serde_json::json;
 use std::{
-    io::{Read, Write},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
     net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex, RwLock},
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-fn create_users() -> DataObjectEnum {
+fn create_users() -> DataBase {
     let user_1 = User {
         id: 1,
         name: "Hlib".to_string(),
@@ -23,30 +33,17 @@ fn create_users() -> DataObjectEnum {
         birth_year: 2000,
         group: crate::UserGroup::User,
     };
-    let db = DataBase {
-        db: vec![user_1, user_2],
-    };
-    DataObjectEnum::DataBase(db)
+    DataBase::from_users(vec![user_1, user_2])
 }
 
-fn get_responce(
-    address: &'static str,
-    path: &str,
-    method: &str,
-    body: &str,
-    db: DataObjectEnum,
-) -> (String, String, DataObjectEnum) {
-    let db = Arc::new(Mutex::new(db));
+fn get_responce(path: &str, method: &str, body: &str, db: DataBase) -> (String, String, DataBase) {
+    let db = Arc::new(RwLock::new(db));
     let server_db = Arc::clone(&db);
-    thread::spawn(|| {
-        run_server(address, server_db);
-    });
-
-    thread::sleep(Duration::from_secs(1));
+    let address = start_server(server_db).local_addr();
 
     let mut stream = TcpStream::connect(address).unwrap();
     let request = format!(
-        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
         method,
         path,
         body.len(),
@@ -61,7 +58,7 @@ fn get_responce(
     let response_data: Vec<&str> = status_line.split(" ").collect();
     let response_body: Vec<&str> = response.split("\r\n").collect();
 
-    let db = db.lock().unwrap();
+    let db = db.read().unwrap();
     (
         response_data[1].to_string(),
         response_body.last().unwrap().to_string(),
@@ -69,10 +66,65 @@ fn get_responce(
     )
 }
 
+/// Serves `db` on a free port.
+fn start_server<S: UserStore + ?Sized + 'static>(db: Arc<RwLock<S>>) -> ServerHandle {
+    Server::builder().store(db).start().unwrap()
+}
+
+/// Server cores that must behave the same.
+const CORES: [ServerCore; 2] = [ServerCore::ThreadPool, ServerCore::EventLoop];
+
+fn assert_problem(body: &str, code: &str, field: Option<&str>) {
+    let problem: serde_json::Value = serde_json::from_str(body).unwrap();
+
+    assert_eq!(problem["code"], code);
+    assert!(problem["status"].as_u64().unwrap() >= 400);
+    assert!(problem["detail"].is_string());
+    if let Some(field) = field {
+        assert_eq!(problem["errors"][0]["field"], field);
+    }
+}
+
+fn read_response(reader: &mut BufReader<TcpStream>) -> (String, Vec<String>, String) {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        headers.push(line.trim_end().to_string());
+    }
+
+    let code = status_line
+        .trim_end()
+        .split(" ")
+        .nth(1)
+        .unwrap()
+        .to_string();
+    // Like any client following RFC 9112, a 204 or 304 is taken to end with
+    // its headers whatever they claim, so a body sent anyway would be read
+    // as the next response.
+    let length = match code.as_str() {
+        "204" | "304" => 0,
+        _ => headers
+            .iter()
+            .find_map(|header| header.strip_prefix("Content-Length: "))
+            .map(|length| length.parse::<usize>().unwrap())
+            .unwrap_or(0),
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+
+    (code, headers, String::from_utf8(body).unwrap())
+}
+
 #[test]
 fn test_empty_users() {
-    let (code, response, _) =
-        get_responce("127.0.0.1:7878", "/users", "GET", "", DataObjectEnum::new());
+    let (code, response, _) = get_responce("/users", "GET", "", DataBase::new());
 
     assert_eq!(code, "200".to_string());
     assert_eq!(response, "[]".to_string());
@@ -81,28 +133,22 @@ fn test_empty_users() {
 #[test]
 fn test_show_users() {
     let users = create_users();
-    let users_db = match users.clone() {
-        DataObjectEnum::DataBase(database) => database,
-        _ => panic!("error"),
-    };
-    let (code, response, _) = get_responce("127.0.0.1:7879", "/users", "GET", "", users.clone());
+    let users_db = users.clone();
+    let (code, response, _) = get_responce("/users", "GET", "", users.clone());
 
     let result: Vec<User> = serde_json::from_str(response.as_str()).unwrap();
 
     assert_eq!(code, "200".to_string());
-    assert_eq!(result, users_db.db);
+    assert_eq!(result.iter().collect::<Vec<_>>(), users_db.get_all());
 }
 
 #[test]
 fn test_show_user() {
     let users = create_users();
-    let users_db = match users.clone() {
-        DataObjectEnum::DataBase(database) => database,
-        _ => panic!("error"),
-    };
-    let user_1 = users_db.db[0].clone();
+    let users_db = users.clone();
+    let user_1 = users_db.get_all()[0].clone();
 
-    let (code, response, _) = get_responce("127.0.0.1:7880", "/users/1", "GET", "", users);
+    let (code, response, _) = get_responce("/users/1", "GET", "", users);
 
     let result: User = serde_json::from_str(response.as_str()).unwrap();
 
@@ -113,10 +159,10 @@ fn test_show_user() {
 #[test]
 fn test_invalid_user_id() {
     let users = create_users();
-    let (code, response, _) = get_responce("127.0.0.1:7881", "/users/test/", "GET", "", users);
+    let (code, response, _) = get_responce("/users/test/", "GET", "", users);
 
     assert_eq!(code, "400".to_string());
-    assert_eq!(response, "Invalid input");
+    assert_problem(&response, "invalid_path_parameter", Some("id"));
 }
 
 #[test]
@@ -139,22 +185,18 @@ fn test_adding_user() {
     })
     .to_string();
 
-    let (code, response, db) =
-        get_responce("127.0.0.1:7882", "/users", "POST", body.as_str(), users);
+    let (code, response, db) = get_responce("/users", "POST", body.as_str(), users);
 
-    let users_db = match db {
-        DataObjectEnum::DataBase(database) => database,
-        _ => panic!("error"),
-    };
+    let users_db = db;
 
     assert_eq!(code, "201".to_string());
     assert_eq!(response, "3");
-    assert_eq!(users_db.db[2], user_3);
+    assert_eq!(*users_db.get_all()[2], user_3);
 }
 
 #[test]
 fn test_adding_user_to_empty() {
-    let users = DataObjectEnum::new();
+    let users = DataBase::new();
 
     let user = User {
         id: 0,
@@ -172,26 +214,22 @@ fn test_adding_user_to_empty() {
     })
     .to_string();
 
-    let (code, response, db) =
-        get_responce("127.0.0.1:7894", "/users", "POST", body.as_str(), users);
-    let users_db = match db.clone() {
-        DataObjectEnum::DataBase(database) => database,
-        _ => panic!("error"),
-    };
+    let (code, response, db) = get_responce("/users", "POST", body.as_str(), users);
+    let users_db = db.clone();
 
     assert_eq!(code, "201".to_string());
     assert_eq!(response, "0");
-    assert_eq!(users_db.db[0], user);
+    assert_eq!(*users_db.get_all()[0], user);
 }
 
 #[test]
 fn test_adding_user_invalid_data() {
     let users = create_users();
 
-    let (code, response, _) = get_responce("127.0.0.1:7883", "/users", "POST", "test", users);
+    let (code, response, _) = get_responce("/users", "POST", "test", users);
 
     assert_eq!(code, "400".to_string());
-    assert_eq!(response, "Invalid input");
+    assert_problem(&response, "invalid_json", None);
 }
 #[test]
 fn test_change_user_name() {
@@ -203,15 +241,12 @@ fn test_change_user_name() {
     })
     .to_string();
 
-    let (code, _, db) = get_responce("127.0.0.1:7884", "/users/1", "PATCH", body.as_str(), users);
-    let users_db = match db.clone() {
-        DataObjectEnum::DataBase(database) => database,
-        _ => panic!("error"),
-    };
+    let (code, _, db) = get_responce("/users/1", "PATCH", body.as_str(), users);
+    let users_db = db.clone();
 
     assert_eq!(code, "204".to_string());
     assert_eq!(
-        *users_db.db.get(0).unwrap(),
+        *users_db.get_all()[0],
         User {
             id: 1,
             name: "Test".to_string(),
@@ -231,11 +266,10 @@ fn test_change_user_name_invalid_id() {
     })
     .to_string();
 
-    let (code, response, _) =
-        get_responce("127.0.0.1:7885", "/users/5", "PATCH", body.as_str(), users);
+    let (code, response, _) = get_responce("/users/5", "PATCH", body.as_str(), users);
 
-    assert_eq!(code, "400".to_string());
-    assert_eq!(response, "Invalid input".to_string());
+    assert_eq!(code, "404".to_string());
+    assert_problem(&response, "user_not_found", None);
 }
 
 #[test]
@@ -247,32 +281,28 @@ fn test_change_user_name_invalid_body() {
     })
     .to_string();
 
-    let (code, response, _) =
-        get_responce("127.0.0.1:7886", "/users/1", "PATCH", body.as_str(), users);
+    let (code, response, _) = get_responce("/users/1", "PATCH", body.as_str(), users);
 
     assert_eq!(code, "400".to_string());
-    assert_eq!(response, "Invalid input".to_string());
+    assert_problem(&response, "invalid_input", Some("test"));
 }
 
 #[test]
 fn test_change_user_name_invalid_body_json() {
     let users = create_users();
 
-    let (code, response, _) = get_responce("127.0.0.1:7887", "/users/1", "PATCH", "test", users);
+    let (code, response, _) = get_responce("/users/1", "PATCH", "test", users);
 
     assert_eq!(code, "400".to_string());
-    assert_eq!(response, "Invalid input".to_string());
+    assert_problem(&response, "invalid_json", None);
 }
 
 #[test]
 fn test_delete_user() {
     let users = create_users();
 
-    let (code, response, db) = get_responce("127.0.0.1:7892", "/users/2", "DELETE", "", users);
-    let users_db = match db.clone() {
-        DataObjectEnum::DataBase(database) => database,
-        _ => panic!("error"),
-    };
+    let (code, response, db) = get_responce("/users/2", "DELETE", "", users);
+    let users_db = db.clone();
 
     let expected_db = vec![User {
         id: 1,
@@ -283,16 +313,1462 @@ fn test_delete_user() {
     }];
 
     assert_eq!(code, "204".to_string());
-    assert_eq!(response, "Removed user".to_string());
-    assert_eq!(users_db.db, expected_db);
+    assert_eq!(response, "".to_string());
+    let stored: Vec<User> = users_db.get_all().into_iter().cloned().collect();
+    assert_eq!(stored, expected_db);
 }
 
 #[test]
 fn test_delete_user_invalid_id() {
     let users = create_users();
 
-    let (code, response, _) = get_responce("127.0.0.1:7893", "/users/3", "DELETE", "", users);
+    let (code, response, _) = get_responce("/users/3", "DELETE", "", users);
 
-    assert_eq!(code, "400".to_string());
-    assert_eq!(response, "Invalid input".to_string());
+    assert_eq!(code, "404".to_string());
+    assert_problem(&response, "user_not_found", None);
+}
+
+#[test]
+fn test_keep_alive_serves_multiple_requests() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer
+        .write_all(b"GET /users/1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (code, headers, body) = read_response(&mut reader);
+    let user: User = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "200");
+    assert!(headers.contains(&"Connection: keep-alive".to_string()));
+    assert_eq!(user.id, 1);
+
+    writer
+        .write_all(b"GET /users/2 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (code, headers, body) = read_response(&mut reader);
+    let user: User = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "200");
+    assert!(headers.contains(&"Connection: close".to_string()));
+    assert_eq!(user.id, 2);
+
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+}
+
+#[test]
+fn test_pipelined_requests_are_answered_in_order() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let body = json!({ "name": "Piped" }).to_string();
+    let requests = format!(
+        "GET /users/2 HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         PATCH /users/1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}\
+         GET /users/1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        body.len(),
+        body
+    );
+    writer.write_all(requests.as_bytes()).unwrap();
+
+    let (code, _, body) = read_response(&mut reader);
+    let user: User = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "200");
+    assert_eq!(user.id, 2);
+
+    let (code, headers, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+    assert!(!headers
+        .iter()
+        .any(|header| header.starts_with("Content-Length")));
+
+    let (code, _, body) = read_response(&mut reader);
+    let user: User = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "200");
+    assert_eq!(user.name, "Piped");
+}
+
+#[test]
+fn test_http_1_0_closes_connection_by_default() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer.write_all(b"GET /users/1 HTTP/1.0\r\n\r\n").unwrap();
+    let (code, headers, _) = read_response(&mut reader);
+    assert_eq!(code, "200");
+    assert!(headers.contains(&"Connection: close".to_string()));
+
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+}
+
+#[test]
+fn test_idle_connection_is_closed_after_timeout() {
+    for core in CORES {
+        idle_connection_is_closed_after_timeout(core);
+    }
+}
+
+fn idle_connection_is_closed_after_timeout(core: ServerCore) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let config = ServerConfig {
+        keep_alive_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let address = Server::builder()
+        .store(db)
+        .config(config)
+        .core(core)
+        .start()
+        .unwrap()
+        .local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer
+        .write_all(b"GET /users/1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "200");
+
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+}
+
+fn send_raw(request: &[u8]) -> (String, String) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let (code, _, body) = read_response(&mut reader);
+    (code, body)
+}
+
+#[test]
+fn test_malformed_request_line() {
+    let (code, _) = send_raw(b"GARBAGE\r\n\r\n");
+
+    assert_eq!(code, "400");
+}
+
+#[test]
+fn test_unsupported_http_version() {
+    let (code, _) = send_raw(b"GET /users HTTP/2.0\r\n\r\n");
+
+    assert_eq!(code, "505");
+}
+
+#[test]
+fn test_non_utf8_body() {
+    let (code, response) =
+        send_raw(b"POST /users HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\n\xff\xfe");
+
+    assert_eq!(code, "400");
+    assert_problem(&response, "invalid_json", None);
+}
+
+#[test]
+fn test_too_large_body() {
+    let (code, _) = send_raw(b"POST /users HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n");
+
+    assert_eq!(code, "413");
+}
+
+#[test]
+fn test_server_survives_disconnect_mid_request() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    for _ in 0..8 {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"POST /users HTTP/1.1\r\nContent-Length: 10\r\n\r\nab")
+            .unwrap();
+    }
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"GET /users/1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut BufReader::new(stream));
+
+    assert_eq!(code, "200");
+}
+
+#[test]
+fn test_method_not_allowed() {
+    let (code, response) = send_raw(b"DELETE /users HTTP/1.1\r\nConnection: close\r\n\r\n");
+
+    assert_eq!(code, "405");
+    assert_problem(&response, "method_not_allowed", None);
+}
+
+#[test]
+fn test_mounting_additional_routes() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let mut router = users_router(db);
+    router.get("/health", |_, _| Ok(Response::new(200, "ok".to_string())));
+    let address = Server::builder()
+        .router(router)
+        .start()
+        .unwrap()
+        .local_addr();
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\n\r\nPUT /health HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let (code, _, body) = read_response(&mut reader);
+    assert_eq!(code, "200");
+    assert_eq!(body, "ok");
+
+    let (code, headers, _) = read_response(&mut reader);
+    assert_eq!(code, "405");
+    assert!(headers.contains(&"Allow: GET".to_string()));
+}
+
+#[test]
+fn test_users_pagination() {
+    let mut db = DataBase::new();
+    for id in [5, 1, 3, 2, 4] {
+        db.add_entry(
+            User {
+                id,
+                name: format!("user{id}"),
+                lastname: "test".to_string(),
+                birth_year: 2000,
+                group: UserGroup::User,
+            },
+            Some(id),
+        )
+        .unwrap();
+    }
+    let db = Arc::new(RwLock::new(db));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer
+        .write_all(b"GET /users?limit=2&offset=2 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, headers, body) = read_response(&mut reader);
+    let users: Vec<User> = serde_json::from_str(&body).unwrap();
+    let ids: Vec<u32> = users.iter().map(|user| user.id).collect();
+    assert_eq!(code, "200");
+    assert_eq!(ids, vec![3, 4]);
+    assert!(headers.contains(&"X-Total-Count: 5".to_string()));
+    assert!(headers.contains(
+        &"Link: </users?limit=2&offset=4>; rel=\"next\", </users?limit=2&offset=0>; rel=\"prev\""
+            .to_string()
+    ));
+
+    let next = headers
+        .iter()
+        .find_map(|header| header.strip_prefix("Link: <"))
+        .and_then(|link| link.split_once('>'))
+        .map(|(next, _)| next.to_string())
+        .unwrap();
+    writer
+        .write_all(format!("GET {next} HTTP/1.1\r\n\r\n").as_bytes())
+        .unwrap();
+    let (code, _, body) = read_response(&mut reader);
+    let users: Vec<User> = serde_json::from_str(&body).unwrap();
+    let ids: Vec<u32> = users.iter().map(|user| user.id).collect();
+    assert_eq!(code, "200");
+    assert_eq!(ids, vec![5]);
+
+    writer
+        .write_all(b"GET /users?limit=2&cursor=00 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "400");
+
+    writer
+        .write_all(b"GET /users?limit=0 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "400");
+}
+
+#[test]
+fn test_users_filters() {
+    let mut db = DataBase::new();
+    for (name, lastname, birth_year, group) in [
+        ("Anna", "Nowak", 1990, UserGroup::Admin),
+        ("Adam", "Kowalski", 1985, UserGroup::User),
+        ("Ewa", "Nowicka", 2001, UserGroup::Admin),
+        ("Jan", "Nowak", 1995, UserGroup::Premium),
+    ] {
+        db.add_entry(
+            User {
+                id: 0,
+                name: name.to_string(),
+                lastname: lastname.to_string(),
+                birth_year,
+                group,
+            },
+            None,
+        )
+        .unwrap();
+    }
+    let db = Arc::new(RwLock::new(db));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut get_ids = |target: &str| {
+        writer
+            .write_all(format!("GET {target} HTTP/1.1\r\n\r\n").as_bytes())
+            .unwrap();
+        let (code, headers, body) = read_response(&mut reader);
+        let ids: Vec<u32> = if code == "200" {
+            let users: Vec<User> = serde_json::from_str(&body).unwrap();
+            users.iter().map(|user| user.id).collect()
+        } else {
+            Vec::new()
+        };
+        (code, headers, ids)
+    };
+
+    assert_eq!(get_ids("/users?group=admin").2, vec![0, 2]);
+    assert_eq!(get_ids("/users?birth_year=1985").2, vec![1]);
+    assert_eq!(
+        get_ids("/users?birth_year_gte=1990&birth_year_lte=2000").2,
+        vec![0, 3]
+    );
+    assert_eq!(
+        get_ids("/users?lastname_prefix=Now&name_prefix=J").2,
+        vec![3]
+    );
+
+    let (code, headers, ids) = get_ids("/users?lastname_prefix=Now&limit=1");
+    assert_eq!(code, "200");
+    assert_eq!(ids, vec![0]);
+    assert!(headers.contains(&"X-Total-Count: 3".to_string()));
+    assert!(headers.iter().any(
+        |header| header.contains("?cursor=") && header.contains("lastname_prefix=Now&limit=1")
+    ));
+
+    assert_eq!(get_ids("/users?group=owner").0, "400");
+    assert_eq!(get_ids("/users?birth_year=abc").0, "400");
+}
+
+#[test]
+fn test_users_sort_and_fields() {
+    let mut db = DataBase::new();
+    for (name, lastname, birth_year) in [
+        ("Anna", "Nowak", 1990),
+        ("Adam", "Kowalski", 1985),
+        ("Ewa", "Adamska", 1990),
+        ("Jan", "Nowak", 2001),
+    ] {
+        db.add_entry(
+            User {
+                id: 0,
+                name: name.to_string(),
+                lastname: lastname.to_string(),
+                birth_year,
+                group: UserGroup::User,
+            },
+            None,
+        )
+        .unwrap();
+    }
+    let db = Arc::new(RwLock::new(db));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut get = |target: &str| {
+        writer
+            .write_all(format!("GET {target} HTTP/1.1\r\n\r\n").as_bytes())
+            .unwrap();
+        read_response(&mut reader)
+    };
+
+    let (code, _, body) = get("/users?sort=-birth_year,lastname&fields=id,name");
+    assert_eq!(code, "200");
+    assert_eq!(
+        body,
+        r#"[{"id":3,"name":"Jan"},{"id":2,"name":"Ewa"},{"id":0,"name":"Anna"},{"id":1,"name":"Adam"}]"#
+    );
+
+    let (_, headers, body) = get("/users?sort=-birth_year,lastname&fields=id&limit=2");
+    assert_eq!(body, r#"[{"id":3},{"id":2}]"#);
+    let next = headers
+        .iter()
+        .find_map(|header| header.strip_prefix("Link: <"))
+        .and_then(|link| link.split_once('>'))
+        .map(|(next, _)| next.to_string())
+        .unwrap();
+    assert!(next.contains("cursor="));
+    let (_, _, body) = get(&next);
+    assert_eq!(body, r#"[{"id":0},{"id":1}]"#);
+
+    let (code, _, body) = get("/users/1?fields=lastname,birth_year");
+    assert_eq!(code, "200");
+    assert_eq!(body, r#"{"birth_year":1985,"lastname":"Kowalski"}"#);
+
+    assert_eq!(get("/users?sort=password").0, "400");
+    assert_eq!(get("/users?fields=id,password").0, "400");
+    assert_eq!(get("/users/1?fields=password").0, "400");
+}
+
+#[test]
+fn test_problem_details_distinguish_field_errors() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut patch = |body: &str| {
+        writer
+            .write_all(
+                format!(
+                    "PATCH /users/1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        read_response(&mut reader)
+    };
+
+    let (code, headers, body) = patch(&json!({ "group": "owner" }).to_string());
+    let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "400");
+    assert!(headers.contains(&"Content-Type: application/problem+json".to_string()));
+    assert_eq!(problem["status"], 400);
+    assert_eq!(problem["errors"][0]["field"], "group");
+    assert_eq!(problem["errors"][0]["code"], "unknown_value");
+
+    let (code, _, body) = patch(&json!({ "birth_year": "soon" }).to_string());
+    let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "400");
+    assert_eq!(problem["errors"][0]["field"], "birth_year");
+    assert_eq!(problem["errors"][0]["code"], "not_a_number");
+}
+
+#[test]
+fn test_show_missing_user() {
+    let users = create_users();
+
+    let (code, response, _) = get_responce("/users/999", "GET", "", users);
+
+    assert_eq!(code, "404".to_string());
+    assert_problem(&response, "user_not_found", None);
+}
+
+#[test]
+fn test_repeated_delete_is_idempotent() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server_db = Arc::clone(&db);
+    let address = start_server(server_db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer
+        .write_all(b"DELETE /users/2 HTTP/1.1\r\n\r\nDELETE /users/2 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+    let (code, _, body) = read_response(&mut reader);
+    assert_eq!(code, "404");
+    assert_problem(&body, "user_not_found", None);
+
+    let users_db = db.read().unwrap().clone();
+    let ids: Vec<u32> = users_db.get_all().iter().map(|user| user.id).collect();
+    assert_eq!(ids, vec![1]);
+}
+
+#[test]
+fn test_conditional_get() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let header = |headers: &[String], name: &str| {
+        headers
+            .iter()
+            .find_map(|header| header.strip_prefix(&format!("{name}: ")))
+            .unwrap()
+            .to_string()
+    };
+
+    for (round, path) in ["/users/1", "/users?sort=name"].into_iter().enumerate() {
+        writer
+            .write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes())
+            .unwrap();
+        let (code, headers, _) = read_response(&mut reader);
+        assert_eq!(code, "200");
+        let etag = header(&headers, "ETag");
+        let last_modified = header(&headers, "Last-Modified");
+
+        writer
+            .write_all(format!("GET {path} HTTP/1.1\r\nIf-None-Match: {etag}\r\n\r\n").as_bytes())
+            .unwrap();
+        let (code, headers, body) = read_response(&mut reader);
+        assert_eq!(code, "304", "{path}");
+        assert_eq!(header(&headers, "ETag"), etag);
+        assert!(!headers
+            .iter()
+            .any(|header| header.starts_with("Content-Length")));
+        assert_eq!(body, "");
+
+        writer
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nIf-Modified-Since: {last_modified}\r\n\r\n")
+                    .as_bytes(),
+            )
+            .unwrap();
+        let (code, _, _) = read_response(&mut reader);
+        assert_eq!(code, "304", "{path}");
+
+        let body = json!({"name": format!("Changed{round}")}).to_string();
+        writer
+            .write_all(
+                format!(
+                    "PATCH /users/1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let (code, _, _) = read_response(&mut reader);
+        assert_eq!(code, "204");
+
+        writer
+            .write_all(format!("GET {path} HTTP/1.1\r\nIf-None-Match: {etag}\r\n\r\n").as_bytes())
+            .unwrap();
+        let (code, headers, body) = read_response(&mut reader);
+        assert_eq!(code, "200", "{path}");
+        assert_ne!(header(&headers, "ETag"), etag);
+        assert!(body.contains("Changed"));
+    }
+}
+
+#[test]
+fn test_if_match_rejects_stale_writes() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server_db = Arc::clone(&db);
+    let address = start_server(server_db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let etag = |writer: &mut TcpStream, reader: &mut BufReader<TcpStream>| {
+        writer.write_all(b"GET /users/1 HTTP/1.1\r\n\r\n").unwrap();
+        let (_, headers, _) = read_response(reader);
+        headers
+            .iter()
+            .find_map(|header| header.strip_prefix("ETag: "))
+            .unwrap()
+            .to_string()
+    };
+    let patch = |writer: &mut TcpStream, etag: &str, name: &str| {
+        let body = json!({ "name": name }).to_string();
+        let request = format!(
+            "PATCH /users/1 HTTP/1.1\r\nIf-Match: {etag}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        writer.write_all(request.as_bytes()).unwrap();
+    };
+
+    let first_read = etag(&mut writer, &mut reader);
+    patch(&mut writer, &first_read, "First");
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+
+    patch(&mut writer, &first_read, "Second");
+    let (code, _, body) = read_response(&mut reader);
+    assert_eq!(code, "412");
+    assert_problem(&body, "precondition_failed", None);
+
+    writer
+        .write_all(format!("DELETE /users/1 HTTP/1.1\r\nIf-Match: {first_read}\r\n\r\n").as_bytes())
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "412");
+
+    let current = etag(&mut writer, &mut reader);
+    writer
+        .write_all(format!("DELETE /users/1 HTTP/1.1\r\nIf-Match: {current}\r\n\r\n").as_bytes())
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+
+    let users_db = db.read().unwrap().clone();
+    let ids: Vec<u32> = users_db.get_all().iter().map(|user| user.id).collect();
+    assert_eq!(ids, vec![2]);
+}
+
+#[test]
+fn test_if_match_can_be_required() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let config = ServerConfig {
+        require_if_match: true,
+        ..ServerConfig::default()
+    };
+    let address = Server::builder()
+        .store(db)
+        .config(config)
+        .start()
+        .unwrap()
+        .local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer
+        .write_all(b"DELETE /users/1 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, body) = read_response(&mut reader);
+    assert_eq!(code, "428");
+    assert_problem(&body, "precondition_required", None);
+
+    writer
+        .write_all(b"DELETE /users/1 HTTP/1.1\r\nIf-Match: *\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+
+    let mut put = |path: &str, condition: &str| {
+        let body =
+            json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "user"})
+                .to_string();
+        let request = format!(
+            "PUT {path} HTTP/1.1\r\n{condition}Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        writer.write_all(request.as_bytes()).unwrap();
+        read_response(&mut reader)
+    };
+
+    let (code, _, body) = put("/users/2", "");
+    assert_eq!(code, "428");
+    assert_problem(&body, "precondition_required", None);
+    let (code, _, _) = put("/users/2", "If-None-Match: *\r\n");
+    assert_eq!(code, "412");
+    let (code, _, _) = put("/users/2", "If-Match: *\r\n");
+    assert_eq!(code, "200");
+
+    let (code, _, _) = put("/users/40", "");
+    assert_eq!(code, "201");
+    let (code, _, _) = put("/users/41", "If-None-Match: *\r\n");
+    assert_eq!(code, "201");
+}
+
+#[test]
+fn test_put_replaces_or_creates_user() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server_db = Arc::clone(&db);
+    let address = start_server(server_db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut put = |path: &str, body: serde_json::Value| {
+        let body = body.to_string();
+        let request = format!(
+            "PUT {path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        writer.write_all(request.as_bytes()).unwrap();
+        read_response(&mut reader)
+    };
+
+    let (code, _, body) = put(
+        "/users/2",
+        json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "premium"}),
+    );
+    assert_eq!(code, "200");
+    let user: User = serde_json::from_str(&body).unwrap();
+    assert_eq!(user.id, 2);
+    assert_eq!(user.name, "Jan");
+
+    let (code, _, body) = put(
+        "/users/40",
+        json!({"name": "Anna", "lastname": "Kowalska", "birth_year": "1990", "group": "user"}),
+    );
+    assert_eq!(code, "201");
+    let user: User = serde_json::from_str(&body).unwrap();
+    assert_eq!(user.id, 40);
+
+    let (code, _, body) = put("/users/2", json!({"name": "Jan"}));
+    assert_eq!(code, "400");
+    assert_problem(&body, "invalid_input", Some("lastname"));
+
+    let users_db = db.read().unwrap().clone();
+    let expected_db = vec![
+        User {
+            id: 1,
+            name: "Hlib".to_string(),
+            lastname: "Shutov".to_string(),
+            birth_year: 2000,
+            group: UserGroup::Admin,
+        },
+        User {
+            id: 2,
+            name: "Jan".to_string(),
+            lastname: "Nowak".to_string(),
+            birth_year: 1985,
+            group: UserGroup::Premium,
+        },
+        User {
+            id: 40,
+            name: "Anna".to_string(),
+            lastname: "Kowalska".to_string(),
+            birth_year: 1990,
+            group: UserGroup::User,
+        },
+    ];
+    let stored: Vec<User> = users_db.get_all().into_iter().cloned().collect();
+    assert_eq!(stored, expected_db);
+}
+
+#[test]
+fn test_user_ids_are_unique() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut send = |request: String| {
+        writer.write_all(request.as_bytes()).unwrap();
+        read_response(&mut reader)
+    };
+    let post = |body: serde_json::Value| {
+        let body = body.to_string();
+        format!(
+            "POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    };
+    let user = json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "user"});
+
+    let (code, _, _) = send("DELETE /users/2 HTTP/1.1\r\n\r\n".to_string());
+    assert_eq!(code, "204");
+    let (code, _, body) = send(post(user.clone()));
+    assert_eq!(code, "201");
+    assert_eq!(body, "3");
+
+    let mut explicit = user.clone();
+    explicit["id"] = json!("1");
+    let (code, _, body) = send(post(explicit));
+    assert_eq!(code, "409");
+    assert_problem(&body, "user_exists", None);
+
+    explicit = user.clone();
+    explicit["id"] = json!("20");
+    let (code, _, body) = send(post(explicit));
+    assert_eq!(code, "201");
+    assert_eq!(body, "20");
+    let (_, _, body) = send(post(user));
+    assert_eq!(body, "21");
+}
+
+/// A store defined outside the crate, serving a fixed list of users.
+struct ReadOnlyStore {
+    users: Vec<User>,
+}
+
+impl UserStore for ReadOnlyStore {
+    fn add_entry(&mut self, _: User, _: Option<u32>) -> Result<u32, ApiError> {
+        Err(ApiError::new(405, "read_only", "This store is read only"))
+    }
+    fn remove_entry(&mut self, _: u32) -> Result<(), ApiError> {
+        Err(ApiError::new(405, "read_only", "This store is read only"))
+    }
+    fn change_user(&mut self, _: u32, _: Vec<UserEnum>) -> Result<(), ApiError> {
+        Err(ApiError::new(405, "read_only", "This store is read only"))
+    }
+    fn get_one(&self, id: u32) -> Result<User, ApiError> {
+        self.users
+            .iter()
+            .find(|user| user.id == id)
+            .cloned()
+            .ok_or_else(|| ApiError::user_not_found(id))
+    }
+    fn get_all(&self) -> Result<Vec<User>, ApiError> {
+        Ok(self.users.clone())
+    }
+    fn query(&self, filter: &UserFilter) -> Result<Vec<User>, ApiError> {
+        Ok(self
+            .users
+            .iter()
+            .filter(|user| filter.matches(user))
+            .cloned()
+            .collect())
+    }
+    fn get_meta(&self, id: u32) -> Result<Option<RecordMeta>, ApiError> {
+        Ok(self.get_one(id).ok().map(|_| RecordMeta::default()))
+    }
+    fn last_modified(&self) -> Result<SystemTime, ApiError> {
+        Ok(SystemTime::UNIX_EPOCH)
+    }
+}
+
+#[test]
+fn test_custom_store() {
+    let store = ReadOnlyStore {
+        users: create_users().get_all().into_iter().cloned().collect(),
+    };
+    let db: Arc<RwLock<dyn UserStore>> = Arc::new(RwLock::new(store));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    writer
+        .write_all(b"GET /users?group=admin HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, body) = read_response(&mut reader);
+    let users: Vec<User> = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "200");
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].id, 1);
+
+    writer
+        .write_all(b"DELETE /users/1 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, body) = read_response(&mut reader);
+    assert_eq!(code, "405");
+    assert_problem(&body, "read_only", None);
+}
+
+#[test]
+fn test_acknowledged_writes_are_durable() {
+    let path = std::env::temp_dir().join(format!("rust_api_it_{}.wal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let db = Arc::new(RwLock::new(DurableDataBase::open(&path).unwrap()));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let body = json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "user"})
+        .to_string();
+    writer
+        .write_all(
+            format!(
+                "POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let (code, _, id) = read_response(&mut reader);
+    assert_eq!(code, "201");
+
+    // The running server still owns the log, a second reader sees the user
+    // as soon as it was acknowledged.
+    let restored = DurableDataBase::open(&path).unwrap();
+    let user = restored.get_one(id.parse().unwrap()).unwrap();
+    assert_eq!(user.name, "Jan");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_admin_compact() {
+    let path = std::env::temp_dir().join(format!("rust_api_it_{}_compact.wal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut db = DurableDataBase::open(&path).unwrap();
+    for user in create_users().get_all() {
+        db.add_entry(user.clone(), Some(user.id)).unwrap();
+    }
+    let log_len = std::fs::metadata(&path).unwrap().len();
+    let db = Arc::new(RwLock::new(db));
+    let address = start_server(db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    writer
+        .write_all(b"POST /admin/compact HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+    assert!(std::fs::metadata(&path).unwrap().len() < log_len);
+
+    let restored = DurableDataBase::open(&path).unwrap();
+    assert_eq!(restored.get_all(), UserStore::get_all(&create_users()));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(path.with_extension("wal.snapshot"));
+}
+
+#[test]
+fn test_admin_compact_not_supported() {
+    let (code, body, _) = get_responce("/admin/compact", "POST", "", create_users());
+
+    assert_eq!(code, "501");
+    assert_problem(&body, "not_supported", None);
+}
+
+#[test]
+fn test_sqlite_store() {
+    let mut store = SqliteStore::open_in_memory().unwrap();
+    for user in create_users().get_all() {
+        store.add_entry(user.clone(), Some(user.id)).unwrap();
+    }
+    let db = Arc::new(RwLock::new(store));
+    let server_db = Arc::clone(&db);
+    let address = start_server(server_db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let body = json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "admin"})
+        .to_string();
+    writer
+        .write_all(
+            format!(
+                "POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let (code, _, id) = read_response(&mut reader);
+    assert_eq!(code, "201");
+    assert_eq!(id, "3");
+
+    writer
+        .write_all(b"GET /users?group=admin&sort=-birth_year&limit=1 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, headers, body) = read_response(&mut reader);
+    let users: Vec<User> = serde_json::from_str(&body).unwrap();
+    assert_eq!(code, "200");
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].id, 1);
+    assert!(headers.contains(&"X-Total-Count: 2".to_string()));
+
+    writer
+        .write_all(b"DELETE /users/3 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "204");
+    assert_eq!(
+        db.read().unwrap().get_one(3),
+        Err(ApiError::user_not_found(3))
+    );
+}
+
+#[test]
+fn test_transactions_apply_all_or_nothing() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server_db = Arc::clone(&db);
+    let address = start_server(server_db).local_addr();
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut post = |body: serde_json::Value| {
+        let body = body.to_string();
+        writer
+            .write_all(
+                format!(
+                    "POST /transactions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let (code, _, body) = read_response(&mut reader);
+        (code, body)
+    };
+    let new_user =
+        json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "user"});
+
+    let (code, body) = post(json!({"operations": [
+        {"op": "add", "user": new_user},
+        {"op": "change", "id": 9, "user": {"group": "admin"}},
+    ]}));
+    assert_eq!(code, "404");
+    assert_problem(&body, "user_not_found", Some("operations[1]"));
+    assert_eq!(db.read().unwrap().get_all(), create_users().get_all());
+    assert_eq!(db.read().unwrap().next_id(), 3);
+
+    let (code, body) = post(json!({"operations": [
+        {"op": "add", "user": new_user},
+        {"op": "add", "user": new_user},
+        {"op": "change", "id": 2, "user": {"group": "admin"}},
+    ]}));
+    assert_eq!(code, "200");
+    assert_eq!(body, "[3,4,2]");
+    let db = db.read().unwrap();
+    assert_eq!(db.get_all().len(), 4);
+    assert_eq!(db.get_one(2).unwrap().group, UserGroup::Admin);
+}
+
+#[test]
+fn test_shutdown_finishes_requests_in_flight() {
+    for core in CORES {
+        shutdown_finishes_requests_in_flight(core);
+    }
+}
+
+fn shutdown_finishes_requests_in_flight(core: ServerCore) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let mut router = users_router(db);
+    router.get("/slow", |_, _| {
+        thread::sleep(Duration::from_millis(500));
+        Ok(Response::new(200, "done".to_string()))
+    });
+    let server = Server::builder().router(router).core(core).start().unwrap();
+    let address = server.local_addr();
+
+    let idle = TcpStream::connect(address).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut idle_writer = idle.try_clone().unwrap();
+    let mut idle_reader = BufReader::new(idle);
+    idle_writer
+        .write_all(b"GET /users/1 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut idle_reader);
+    assert_eq!(code, "200");
+
+    let busy = thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        read_response(&mut BufReader::new(stream))
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let started = Instant::now();
+    server.shutdown();
+    // The idle connection does not hold shutdown up for its keep-alive timeout.
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let (code, headers, body) = busy.join().unwrap();
+    assert_eq!(code, "200");
+    assert_eq!(body, "done");
+    assert!(headers.contains(&"Connection: close".to_string()));
+
+    let mut rest = String::new();
+    idle_reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+    assert!(TcpStream::connect(address).is_err());
+}
+
+#[test]
+fn test_shutdown_closes_connections_after_timeout() {
+    for core in CORES {
+        shutdown_closes_connections_after_timeout(core);
+    }
+}
+
+fn shutdown_closes_connections_after_timeout(core: ServerCore) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let config = ServerConfig {
+        shutdown_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let server = Server::builder()
+        .store(db)
+        .config(config)
+        .core(core)
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+
+    // The request never completes, so its worker waits for the rest of it.
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET /users/1 HTTP/1.1\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let started = Instant::now();
+    server.shutdown();
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn test_builder_reports_bind_failure() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server = start_server(Arc::clone(&db));
+    let address = server.local_addr();
+    assert_ne!(address.port(), 0);
+
+    let error = Server::builder()
+        .address(&address.to_string())
+        .store(Arc::clone(&db))
+        .start()
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), ErrorKind::AddrInUse);
+
+    let error = Server::builder().start().err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    let error = Server::builder()
+        .store(db)
+        .threads(0)
+        .start()
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    server.shutdown();
+}
+
+#[test]
+fn test_single_thread_server() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server = Server::builder().store(db).threads(1).start().unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .write_all(b"GET /users/2 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (code, _, body) = read_response(&mut BufReader::new(stream));
+    assert_eq!(code, "200");
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["name"],
+        "Wojciech"
+    );
+    server.shutdown();
+}
+
+#[test]
+fn test_panicking_handler_returns_500() {
+    for core in CORES {
+        panicking_handler_returns_500(core);
+    }
+}
+
+fn panicking_handler_returns_500(core: ServerCore) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let poisoner = Arc::clone(&db);
+    let mut router = users_router(db);
+    router.get("/panic", |_, _| panic!("handler failed"));
+    router.get("/poison", move |_, _| {
+        let _users = poisoner.write().unwrap();
+        panic!("handler failed while holding the store");
+    });
+    let server = Server::builder()
+        .router(router)
+        .threads(1)
+        .core(core)
+        .start()
+        .unwrap();
+
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    for path in ["/panic", "/poison"] {
+        writer
+            .write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes())
+            .unwrap();
+        let (code, _, body) = read_response(&mut reader);
+        assert_eq!(code, "500");
+        assert_problem(&body, "internal_error", None);
+
+        // The same connection, served by the only worker, still works and
+        // the store recovered from the poisoned lock.
+        writer.write_all(b"GET /users/1 HTTP/1.1\r\n\r\n").unwrap();
+        let (code, _, _) = read_response(&mut reader);
+        assert_eq!(code, "200");
+    }
+    server.shutdown();
+}
+
+#[test]
+fn test_header_at_the_size_limit_leaves_server_running() {
+    for core in CORES {
+        header_at_the_size_limit_leaves_server_running(core);
+    }
+}
+
+fn header_at_the_size_limit_leaves_server_running(core: ServerCore) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server = Server::builder().store(db).core(core).start().unwrap();
+
+    // Used to overflow the header size budget and panic the parser.
+    let field = format!(
+        "X-Test: {}",
+        "a".repeat(MAX_HEADERS_SIZE - "X-Test: ".len())
+    );
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .write_all(format!("GET /users/1 HTTP/1.1\r\n{field}\r\nX-Next: 1\r\n\r\n").as_bytes())
+        .unwrap();
+    let (code, _, _) = read_response(&mut BufReader::new(stream));
+    assert_eq!(code, "431");
+
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    writer.write_all(b"GET /users/1 HTTP/1.1\r\n\r\n").unwrap();
+    let (code, _, _) = read_response(&mut BufReader::new(stream));
+    assert_eq!(code, "200");
+    server.shutdown();
+}
+
+#[test]
+fn test_thread_pool_respawns_panicked_workers() {
+    let pool = ThreadPool::new(1);
+    pool.execute(|| panic!("job failed"));
+
+    let (sender, receiver) = mpsc::channel();
+    pool.execute(move || sender.send(thread::current().id()).unwrap());
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    drop(pool);
+}
+
+/// Waits until `condition` holds, for at most five seconds.
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_thread_pool_queue_is_bounded() {
+    let pool = ThreadPool::with_capacity(1, 1);
+    let (release, gate) = mpsc::channel::<()>();
+    pool.execute(move || gate.recv().unwrap());
+    wait_until(|| pool.stats().started == 1);
+
+    let (sender, receiver) = mpsc::channel();
+    let queued = sender.clone();
+    assert!(pool.try_execute(move || queued.send(1).unwrap()).is_ok());
+    assert!(pool.try_execute(move || sender.send(2).unwrap()).is_err());
+    assert_eq!(
+        pool.stats(),
+        QueueStats {
+            capacity: 1,
+            queued: 1,
+            peak: 1,
+            started: 1,
+            rejected: 1,
+        }
+    );
+
+    release.send(()).unwrap();
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(1));
+    assert_eq!(pool.stats().queued, 0);
+}
+
+#[test]
+fn test_full_queue_answers_503() {
+    for core in CORES {
+        full_queue_answers_503(core);
+    }
+}
+
+fn full_queue_answers_503(core: ServerCore) {
+    let db = Arc::new(RwLock::new(create_users()));
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let mut router = users_router(db);
+    router.get("/slow", move |_, _| {
+        gate.lock().unwrap().recv().unwrap();
+        Ok(Response::new(200, "done".to_string()))
+    });
+    let server = Server::builder()
+        .router(router)
+        .threads(1)
+        .core(core)
+        .queue_capacity(1)
+        .overload_policy(OverloadPolicy::Reject {
+            retry_after: Duration::from_millis(1500),
+        })
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+
+    let mut busy = TcpStream::connect(address).unwrap();
+    busy.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    wait_until(|| server.queue_stats().started == 1);
+    // The event loop only queues complete requests.
+    let mut queued = TcpStream::connect(address).unwrap();
+    queued
+        .write_all(b"GET /users/1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    wait_until(|| server.queue_stats().queued == 1);
+
+    // The pipelined request is never read, the server must still not
+    // reset the connection before the 503 arrived.
+    let mut rejected = TcpStream::connect(address).unwrap();
+    rejected
+        .write_all(b"GET /users/1 HTTP/1.1\r\n\r\nGET /users/2 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, headers, body) = read_response(&mut BufReader::new(rejected));
+    assert_eq!(code, "503");
+    assert!(headers.contains(&"Retry-After: 2".to_string()));
+    assert_problem(&body, "overloaded", None);
+
+    release.send(()).unwrap();
+    let (code, _, _) = read_response(&mut BufReader::new(busy.try_clone().unwrap()));
+    assert_eq!(code, "200");
+    let (code, _, _) = read_response(&mut BufReader::new(queued));
+    assert_eq!(code, "200");
+
+    let stats = server.queue_stats();
+    assert_eq!((stats.started, stats.rejected, stats.peak), (2, 1, 1));
+    server.shutdown();
+}
+
+#[test]
+fn test_event_loop_holds_requests_while_queue_is_full() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let mut router = users_router(db);
+    router.get("/slow", move |_, _| {
+        gate.lock().unwrap().recv().unwrap();
+        Ok(Response::new(200, "done".to_string()))
+    });
+    let server = Server::builder()
+        .router(router)
+        .threads(1)
+        .core(ServerCore::EventLoop)
+        .queue_capacity(1)
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+
+    let mut busy = TcpStream::connect(address).unwrap();
+    busy.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    wait_until(|| server.queue_stats().started == 1);
+    let clients: Vec<TcpStream> = (0..8)
+        .map(|_| {
+            let mut client = TcpStream::connect(address).unwrap();
+            client
+                .write_all(b"GET /users/1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            client
+        })
+        .collect();
+    wait_until(|| server.queue_stats().queued == 1);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.queue_stats().queued, 1);
+
+    release.send(()).unwrap();
+    let (code, _, _) = read_response(&mut BufReader::new(busy));
+    assert_eq!(code, "200");
+    for client in clients {
+        let (code, _, _) = read_response(&mut BufReader::new(client));
+        assert_eq!(code, "200");
+    }
+
+    let stats = server.queue_stats();
+    assert_eq!((stats.started, stats.rejected, stats.peak), (9, 0, 1));
+    server.shutdown();
+}
+
+#[test]
+fn test_event_loop_serves_partial_and_pipelined_requests() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server = Server::builder()
+        .store(db)
+        .core(ServerCore::EventLoop)
+        .start()
+        .unwrap();
+
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let body = json!({"name": "Jan", "lastname": "Nowak", "birth_year": "1985", "group": "user"})
+        .to_string();
+    let request = format!(
+        "POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    let (head, tail) = request.split_at(request.len() - 10);
+    writer.write_all(head.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    writer.write_all(tail.as_bytes()).unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "201");
+
+    writer
+        .write_all(b"GET /users/1 HTTP/1.1\r\n\r\nGET /users/3 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "200");
+    let (code, _, body) = read_response(&mut reader);
+    assert_eq!(code, "200");
+    assert!(body.contains("Nowak"));
+
+    writer.write_all(b"GARBAGE\r\n\r\n").unwrap();
+    let (code, _, _) = read_response(&mut reader);
+    assert_eq!(code, "400");
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+    server.shutdown();
+}
+
+#[test]
+fn test_event_loop_is_not_stalled_by_slow_clients() {
+    let db = Arc::new(RwLock::new(create_users()));
+    let server = Server::builder()
+        .store(db)
+        .threads(1)
+        .core(ServerCore::EventLoop)
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+
+    // Idle clients and clients stuck in the middle of a request.
+    let slow: Vec<TcpStream> = (0..16)
+        .map(|index| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            if index % 2 == 0 {
+                stream.write_all(b"GET /users/1 HTTP/1.1\r\nHost:").unwrap();
+            }
+            stream
+        })
+        .collect();
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"GET /users/2 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (code, _, _) = read_response(&mut BufReader::new(stream));
+    assert_eq!(code, "200");
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    drop(slow);
+    server.shutdown();
 }